@@ -0,0 +1,350 @@
+
+//! Picking an adapter with `--adapter`
+//!
+//! Selection only looks at `AdapterDesc`s, not at DXGI, so it works the same
+//! on adapters we made up as on the ones `renderer` enumerates.
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Vendor {
+    Amd,            // 0x1002
+    Imgtec,         // 0x1010
+    Nvidia,         // 0x10DE
+    Arm,            // 0x13B5
+    Qualcomm,       // 0x5143
+    Intel,          // 0x8086
+    Microsoft,      // 0x1414
+    Unknown(u32),
+}
+
+impl Vendor {
+    /// Every vendor we know by name.
+    pub fn all() -> &'static [Vendor] {
+        &[
+            Vendor::Amd,
+            Vendor::Imgtec,
+            Vendor::Nvidia,
+            Vendor::Arm,
+            Vendor::Qualcomm,
+            Vendor::Intel,
+            Vendor::Microsoft,
+        ]
+    }
+}
+
+pub fn vid_to_vendor(vid: u32) -> Vendor {
+    match vid {
+        0x1002 => Vendor::Amd,
+        0x1010 => Vendor::Imgtec,
+        0x10DE => Vendor::Nvidia,
+        0x13B5 => Vendor::Arm,
+        0x5143 => Vendor::Qualcomm,
+        0x8086 => Vendor::Intel,
+        0x1414 => Vendor::Microsoft,
+        _      => Vendor::Unknown(vid),
+    }
+}
+
+impl fmt::Display for Vendor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Vendor::Amd          => write!(f, "Amd"),
+            Vendor::Imgtec       => write!(f, "Imgtec"),
+            Vendor::Nvidia       => write!(f, "Nvidia"),
+            Vendor::Arm          => write!(f, "Arm"),
+            Vendor::Qualcomm     => write!(f, "Qualcomm"),
+            Vendor::Intel        => write!(f, "Intel"),
+            Vendor::Microsoft    => write!(f, "Microsoft"),
+            Vendor::Unknown(vid) => write!(f, "Unknown (0x{:x})", vid),
+        }
+    }
+}
+
+/// An adapter's locally unique identifier. It stays the same until the
+/// machine reboots, so it names one adapter even if they get enumerated in a
+/// different order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Luid(pub u64);
+
+impl Luid {
+    pub fn from_parts(high: i32, low: u32) -> Luid {
+        Luid((high as u32 as u64) << 32 | low as u64)
+    }
+}
+
+impl fmt::Display for Luid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:016x}", self.0)
+    }
+}
+
+/// What selection needs to know about one adapter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterDesc {
+    pub description:            String,
+    pub vendor:                 Vendor,
+    pub device_id:              u32,
+    pub luid:                   Luid,
+    pub dedicated_video_memory: u64,
+    pub shared_system_memory:   u64,
+    /// Like WARP, or the Microsoft Basic Render Driver.
+    pub software:               bool,
+}
+
+/// Which adapter `--adapter` asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterSelector {
+    /// The adapter's position in DXGI's enumeration order, from 0.
+    Index(usize),
+    /// Part of the adapter's description, ignoring case.
+    Name(String),
+    Vendor(Vendor),
+    Luid(Luid),
+    /// The hardware adapter with the most dedicated video memory.
+    MaxVram,
+    /// The hardware adapter with the least dedicated video memory, which is
+    /// usually the one built into the CPU.
+    Integrated,
+}
+
+impl Default for AdapterSelector {
+    /// DXGI enumerates the adapter driving the primary display first.
+    fn default() -> AdapterSelector {
+        AdapterSelector::Index(0)
+    }
+}
+
+impl AdapterSelector {
+    /// The index in `adapters` of the one we'd pick, or `None` if nothing
+    /// matches. Ties go to whichever comes first.
+    pub fn select(&self, adapters: &[AdapterDesc]) -> Option<usize> {
+        let mut matching = adapters.iter().enumerate();
+        match *self {
+            AdapterSelector::Index(i) => {
+                if i < adapters.len() { Some(i) } else { None }
+            },
+            AdapterSelector::Name(ref name) => {
+                let name = name.to_lowercase();
+                matching.find(|&(_, adapter)| adapter.description.to_lowercase().contains(&name))
+                        .map(|(i, _)| i)
+            },
+            AdapterSelector::Vendor(vendor) => {
+                matching.find(|&(_, adapter)| adapter.vendor == vendor).map(|(i, _)| i)
+            },
+            AdapterSelector::Luid(luid) => {
+                matching.find(|&(_, adapter)| adapter.luid == luid).map(|(i, _)| i)
+            },
+            AdapterSelector::MaxVram => {
+                // `max_by_key` keeps the last of equal maxima, so reverse to
+                // keep the first.
+                matching.filter(|&(_, adapter)| !adapter.software)
+                        .rev()
+                        .max_by_key(|&(_, adapter)| adapter.dedicated_video_memory)
+                        .map(|(i, _)| i)
+            },
+            AdapterSelector::Integrated => {
+                matching.filter(|&(_, adapter)| !adapter.software)
+                        .min_by_key(|&(_, adapter)| adapter.dedicated_video_memory)
+                        .map(|(i, _)| i)
+            },
+        }
+    }
+}
+
+impl fmt::Display for AdapterSelector {
+    /// Parses back into the same selector.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AdapterSelector::Index(i)       => write!(f, "{}", i),
+            AdapterSelector::Name(ref name) => {
+                // Names that look like something else need saying so.
+                match name.parse::<AdapterSelector>() {
+                    Ok(AdapterSelector::Name(_)) => write!(f, "{}", name),
+                    _                            => write!(f, "name:{}", name),
+                }
+            },
+            AdapterSelector::Vendor(vendor) => write!(f, "{}", vendor.to_string().to_lowercase()),
+            AdapterSelector::Luid(luid)     => write!(f, "{}", luid),
+            AdapterSelector::MaxVram        => write!(f, "max-vram"),
+            AdapterSelector::Integrated     => write!(f, "integrated"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidAdapterSelector;
+
+impl FromStr for AdapterSelector {
+    type Err = InvalidAdapterSelector;
+
+    /// In order, we try:
+    ///
+    /// - `max-vram` or `integrated`
+    /// - an index, like `1`
+    /// - a LUID in hex, like `0x000000000000d1a2`
+    /// - a vendor, like `nvidia`
+    /// - part of the adapter's name, like `radeon`
+    ///
+    /// `name:` in front forces the last one, e.g. `name:1080`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with("name:") {
+            return match s["name:".len()..].trim() {
+                ""   => Err(InvalidAdapterSelector),
+                name => Ok(AdapterSelector::Name(name.to_string())),
+            };
+        }
+        if s.is_empty() {
+            return Err(InvalidAdapterSelector);
+        }
+        if s.eq_ignore_ascii_case("max-vram") {
+            return Ok(AdapterSelector::MaxVram);
+        }
+        if s.eq_ignore_ascii_case("integrated") {
+            return Ok(AdapterSelector::Integrated);
+        }
+        if s.chars().all(|c| c.is_digit(10)) {
+            return s.parse().map(AdapterSelector::Index).map_err(|_| InvalidAdapterSelector);
+        }
+        if s.starts_with("0x") || s.starts_with("0X") {
+            return u64::from_str_radix(&s[2..], 16)
+                .map(|luid| AdapterSelector::Luid(Luid(luid)))
+                .map_err(|_| InvalidAdapterSelector);
+        }
+        let vendor = Vendor::all()
+            .iter()
+            .find(|vendor| vendor.to_string().eq_ignore_ascii_case(s));
+        match vendor {
+            Some(&vendor) => Ok(AdapterSelector::Vendor(vendor)),
+            None          => Ok(AdapterSelector::Name(s.to_string())),
+        }
+    }
+}
+
+/// Made-up adapters, for testing anything that picks between them.
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    pub const GIB: u64 = 1 << 30;
+
+    pub fn adapter(description: &str, vendor: Vendor, luid: u64, vram: u64) -> AdapterDesc {
+        AdapterDesc {
+            description:            description.to_string(),
+            vendor,
+            device_id:              0,
+            luid:                   Luid(luid),
+            dedicated_video_memory: vram,
+            shared_system_memory:   8 * GIB,
+            software:               false,
+        }
+    }
+
+    pub fn warp() -> AdapterDesc {
+        AdapterDesc {
+            software: true,
+            ..adapter("Microsoft Basic Render Driver", Vendor::Microsoft, 0x3000, 0)
+        }
+    }
+
+    /// A laptop: integrated graphics first, then a discrete GPU, then WARP.
+    pub fn laptop() -> Vec<AdapterDesc> {
+        vec![
+            adapter("Intel(R) UHD Graphics 630",       Vendor::Intel,  0x1000, 128 << 20),
+            adapter("NVIDIA GeForce GTX 1080",         Vendor::Nvidia, 0x2000, 8 * GIB),
+            warp(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fixtures::*;
+
+    fn select(selector: &str, adapters: &[AdapterDesc]) -> Option<usize> {
+        selector.parse::<AdapterSelector>().unwrap().select(adapters)
+    }
+
+    #[test]
+    fn selects_by_index() {
+        assert_eq!(select("0", &laptop()), Some(0));
+        assert_eq!(select("2", &laptop()), Some(2));
+        assert_eq!(select("3", &laptop()), None);
+        assert_eq!(select("0", &[]), None);
+    }
+
+    #[test]
+    fn selects_by_part_of_the_name() {
+        assert_eq!(select("geforce", &laptop()), Some(1));
+        assert_eq!(select("GTX 1080", &laptop()), Some(1));
+        assert_eq!(select("uhd graphics", &laptop()), Some(0));
+        // Both Intel's and WARP's descriptions have an "r" in them.
+        assert_eq!(select("name:R", &laptop()), Some(0));
+        assert_eq!(select("radeon", &laptop()), None);
+    }
+
+    #[test]
+    fn selects_by_vendor() {
+        assert_eq!(select("nvidia", &laptop()), Some(1));
+        assert_eq!(select("Intel", &laptop()), Some(0));
+        assert_eq!(select("microsoft", &laptop()), Some(2));
+        assert_eq!(select("amd", &laptop()), None);
+    }
+
+    #[test]
+    fn selects_by_luid() {
+        assert_eq!("0x0000000000002000".parse(), Ok(AdapterSelector::Luid(Luid(0x2000))));
+        assert_eq!("0X2000".parse(), Ok(AdapterSelector::Luid(Luid(0x2000))));
+        assert_eq!("0xnope".parse::<AdapterSelector>(), Err(InvalidAdapterSelector));
+        assert_eq!(Luid::from_parts(1, 0x2000), Luid(0x0000000100002000));
+        assert_eq!(Luid::from_parts(-1, 0), Luid(0xffffffff00000000));
+
+        assert_eq!(select("0x2000", &laptop()), Some(1));
+        assert_eq!(select(&Luid(0x3000).to_string(), &laptop()), Some(2));
+        assert_eq!(select("0x4000", &laptop()), None);
+    }
+
+    #[test]
+    fn max_vram_ignores_software_and_keeps_the_first_of_a_tie() {
+        assert_eq!(select("max-vram", &laptop()), Some(1));
+
+        let twins = vec![
+            warp(),
+            adapter("NVIDIA GeForce GTX 1080", Vendor::Nvidia, 0x1000, 8 * GIB),
+            adapter("NVIDIA GeForce GTX 1080", Vendor::Nvidia, 0x2000, 8 * GIB),
+        ];
+        assert_eq!(select("max-vram", &twins), Some(1));
+        assert_eq!(select("max-vram", &[warp()]), None);
+    }
+
+    #[test]
+    fn integrated_is_the_hardware_adapter_with_the_least_vram() {
+        assert_eq!(select("integrated", &laptop()), Some(0));
+
+        // Without integrated graphics, we get the smaller discrete GPU.
+        let desktop = vec![
+            adapter("NVIDIA GeForce GTX 1080", Vendor::Nvidia, 0x1000, 8 * GIB),
+            adapter("AMD Radeon RX 560",       Vendor::Amd,    0x2000, 4 * GIB),
+            warp(),
+        ];
+        assert_eq!(select("integrated", &desktop), Some(1));
+
+        // WARP doesn't count, even though it has no VRAM at all.
+        assert_eq!(select("integrated", &[warp()]), None);
+    }
+
+    #[test]
+    fn selectors_round_trip() {
+        for s in &["0", "12", "max-vram", "integrated", "nvidia", "0x0000000000002000",
+                   "geforce", "name:1080", "name:amd", "name:max-vram"] {
+            let selector: AdapterSelector = s.parse().unwrap();
+            assert_eq!(selector.to_string(), *s);
+            assert_eq!(selector.to_string().parse(), Ok(selector));
+        }
+    }
+}
@@ -1,40 +1,1164 @@
 
-#[derive(Debug, Copy, Clone)]
+use std::{
+    collections::HashMap,
+    env,
+    fmt,
+    fs,
+    io::{self, Write},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+use adapter::AdapterSelector;
+use error::{
+    json_string,
+    Category,
+    HResult,
+};
+use fallback::FallbackPolicy;
+use info_queue::{
+    MessageFilter,
+    MessageSeverity,
+};
+use overlay::Overlays;
+
+/// What we look for in the working directory and in `<config dir>/dx12/`.
+pub const CONFIG_FILE_NAME: &str = "dx12.toml";
+
+/// Profiles everyone gets. Config files can add their own under
+/// `[profiles.<name>]`, or replace these.
+const BUILTIN_PROFILES: &str = r#"
+# Runs on build machines without a GPU, and fails loudly instead of falling
+# back to something we didn't ask for.
+[ci]
+force_warp    = true
+enable_debug  = true
+feature_level = "12_0"
+fallback      = "none"
+color         = "never"
+error_format  = "json"
+
+# Nothing that slows us down, and no quietly measuring WARP instead.
+[perf]
+enable_debug = false
+trace_api    = false
+fallback     = "none"
+
+# Everything that helps track a bug down.
+[debug]
+enable_debug = true
+debug_break  = "error"
+trace_api    = true
+"#;
+
+/// Every key that `Config::set()` understands, in the order we print them.
+/// Each can also be set with a `DX12_<KEY>` environment variable.
+pub const KEYS: &[&str] = &[
+    "force_warp",
+    "adapter",
+    "fallback",
+    "enable_debug",
+    "debug_filter",
+    "debug_break",
+    "feature_level",
+    "fullscreen",
+    "resolution",
+    "vsync",
+    "clear_color",
+    "overlays",
+    "color",
+    "error_theme",
+    "error_format",
+    "crash_report",
+    "trace_api",
+];
+
+/// Keys that `run` applies as soon as a config file changes. Changing any
+/// other key while running only tells you to restart.
+pub const LIVE_KEYS: &[&str] = &[
+    "resolution",
+    "vsync",
+    "clear_color",
+    "overlays",
+];
+
+#[derive(Debug, Clone)]
 pub struct Config {
+    pub command: Command,
     pub force_warp: bool,
+    pub adapter: AdapterSelector,
+    pub fallback: FallbackPolicy,
     pub enable_debug: bool,
-    pub feature_level: Dx12FeatureLevel,
+    pub debug_filter: MessageFilter,
+    pub debug_break: Option<MessageSeverity>,
+    pub feature_level: FeatureLevelRange,
     pub fullscreen: bool,
-    pub window_width:  Option<u32>,
-    pub window_height: Option<u32>,
+    pub resolution: Resolution,
+    pub vsync: bool,
+    pub clear_color: ClearColor,
+    pub overlays: Overlays,
+    pub color: ColorMode,
+    pub error_theme: Option<PathBuf>,
+    pub error_format: ErrorFormat,
+    pub crash_report: bool,
+    pub trace_api: bool,
+    /// Where `run` should write a manifest. Not a key, so replaying a
+    /// manifest doesn't overwrite it.
+    pub manifest: Option<PathBuf>,
+    /// Built-in profiles, then those from config files, which replace
+    /// built-ins with the same name.
+    pub profiles: Vec<Profile>,
+    /// Where each key was set from, when it wasn't left as the default.
+    pub sources: HashMap<String, Source>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            command:       Command::Run,
+            force_warp:    false,
+            adapter:       AdapterSelector::default(),
+            fallback:      FallbackPolicy::default(),
+            enable_debug:  true,
+            debug_filter:  MessageFilter::default(),
+            debug_break:   None,
+            feature_level: FeatureLevelRange::default(),
+            fullscreen:    false,
+            resolution:    Resolution::default(),
+            vsync:         true,
+            clear_color:   ClearColor::default(),
+            overlays:      Overlays::default(),
+            color:         ColorMode::Auto,
+            error_theme:   None,
+            error_format:  ErrorFormat::Human,
+            crash_report:  true,
+            trace_api:     false,
+            manifest:      None,
+            profiles:      builtin_profiles(),
+            sources:       HashMap::new(),
+        }
+    }
 }
 
 impl Config {
+    /// Start from the defaults, then apply each config file from
+    /// `config_file_paths()`, then `DX12_*` environment variables, then
+    /// `--replay-config`, then each `--profile`, then the rest of the command
+    /// line. Later ones win.
+    ///
+    /// Exits with `Category::InvalidConfig`'s code if any of them are bad.
     pub fn load() -> Config {
-        let matches = get_arg_matches();
-        Config {
-            force_warp:    matches.is_present("force-warp"),
-            enable_debug:  !matches.is_present("no-debug"),
-            feature_level: matches.value_of("feature-level")
-                                   .expect("No feature level specified?")
-                                   // Clap verifies this:
-                                   .parse::<Dx12FeatureLevel>().unwrap(),
-            fullscreen: matches.is_present("fullscreen"),
-            // ! This silently drops invalid width/height values !
-            window_width:  matches.value_of("window-width")
-                                  .and_then(|utf8| utf8.parse().ok()),
-            window_height: matches.value_of("window-height")
-                                  .and_then(|utf8| utf8.parse().ok()),
+        match Config::resolve(&get_arg_matches()) {
+            Ok(config) => config,
+            Err(err)   => {
+                eprintln!("error: {}", err);
+                process::exit(Category::InvalidConfig.exit_code());
+            },
+        }
+    }
+
+    /// Do everything `load()` does again, to pick up changed config files.
+    /// Bad values are returned rather than exiting, since we're running.
+    pub fn reload() -> Result<Config, ConfigError> {
+        Config::resolve(&get_arg_matches())
+    }
+
+    fn resolve(matches: &::clap::ArgMatches) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        for path in config_file_paths() {
+            config.apply_file(&path)?;
+        }
+        config.apply_env(env::vars_os())?;
+        if let Some(path) = matches.value_of_os("replay-config") {
+            config.apply_replay(Path::new(path))?;
+        }
+        for name in matches.values_of("profile").into_iter().flat_map(|names| names) {
+            config.apply_profile(name)?;
+        }
+        config.apply_matches(matches);
+        Ok(config)
+    }
+
+    /// Take every `LIVE_KEYS` value from `other`, and where it came from.
+    pub fn apply_live(&mut self, other: &Config) {
+        self.resolution  = other.resolution;
+        self.vsync       = other.vsync;
+        self.clear_color = other.clear_color;
+        self.overlays    = other.overlays;
+        for key in LIVE_KEYS {
+            match other.sources.get(*key) {
+                Some(source) => self.sources.insert(key.to_string(), source.clone()),
+                None         => self.sources.remove(*key),
+            };
+        }
+    }
+
+    /// Set every key in the TOML file at `path`. A missing file is fine.
+    pub fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(ConfigError::new(Source::File(path.to_path_buf()),
+                                                    None,
+                                                    err.to_string())),
+        };
+        self.apply_toml(&text, path)
+    }
+
+    /// Set every key in `text`, which was read from `path`. Relative paths
+    /// are relative to the file's directory.
+    pub fn apply_toml(&mut self, text: &str, path: &Path) -> Result<(), ConfigError> {
+        let value = parse_toml(text, path)?;
+        self.apply_table(&value, path)
+    }
+
+    /// Set every key in the `[config]` table of a manifest that `run
+    /// --manifest` wrote. Anything `config --dump` wrote works too, since
+    /// that's the same table without the header.
+    ///
+    /// Unlike config files, a missing file is an error: we were asked for it.
+    pub fn apply_replay(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| ConfigError::new(Source::File(path.to_path_buf()),
+                                            None,
+                                            err.to_string()))?;
+        let value = parse_toml(&text, path)?;
+        match value.get("config") {
+            Some(config) => self.apply_table(config, path),
+            None         => self.apply_table(&value, path),
+        }
+    }
+
+    fn apply_table(&mut self, value: &toml::Value, path: &Path) -> Result<(), ConfigError> {
+        let source = Source::File(path.to_path_buf());
+        let table = match value.as_table() {
+            Some(table) => table,
+            None        => return Err(ConfigError::new(source,
+                                                       None,
+                                                       "expected a table".into())),
+        };
+
+        for (key, value) in table {
+            if key == "profiles" {
+                self.add_profiles(value, &source)?;
+                continue;
+            }
+            self.set(key, value)
+                .map_err(|message| ConfigError::new(source.clone(), Some(key), message))?;
+            self.sources.insert(key.clone(), source.clone());
+        }
+        self.adapter_beats_force_warp(table.keys().map(|key| key.as_str()), &source);
+        if let Some(ref mut theme) = self.error_theme {
+            if table.contains_key("error_theme") && theme.is_relative() {
+                let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                *theme = dir.join(&*theme);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set every key that has a `DX12_<KEY>` variable in `vars`.
+    /// Other variables are ignored.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), ConfigError>
+        where I: IntoIterator<Item=(OsString, OsString)>
+    {
+        let mut keys = vec![];
+        let mut adapter_source = None;
+        for (name, value) in vars {
+            let name = match name.into_string() {
+                Ok(name) => name,
+                Err(_)   => continue,
+            };
+            let key = match env_key(&name) {
+                Some(key) => key,
+                None      => continue,
+            };
+            let source = Source::Env(name);
+            let value = value.into_string().map_err(|_| {
+                ConfigError::new(source.clone(), Some(key), "is not valid unicode".into())
+            })?;
+            self.set(key, &toml::Value::String(value))
+                .map_err(|message| ConfigError::new(source.clone(), Some(key), message))?;
+            self.sources.insert(key.to_string(), source.clone());
+            keys.push(key);
+            if key == "adapter" {
+                adapter_source = Some(source);
+            }
+        }
+        if let Some(source) = adapter_source {
+            self.adapter_beats_force_warp(keys, &source);
+        }
+        Ok(())
+    }
+
+    /// Asking for an adapter beats an earlier layer's `force_warp`, like
+    /// `--adapter` beats a config file's. `keys` are everything one layer
+    /// set, so a layer that sets both keeps its own `force_warp`.
+    fn adapter_beats_force_warp<'a, I>(&mut self, keys: I, source: &Source)
+        where I: IntoIterator<Item=&'a str>
+    {
+        let keys: Vec<&str> = keys.into_iter().collect();
+        if keys.contains(&"adapter") && !keys.contains(&"force_warp") {
+            self.force_warp = false;
+            self.sources.insert("force_warp".to_string(), source.clone());
+        }
+    }
+
+    /// Remember each `[profiles.<name>]` table in `value`. We only check
+    /// their keys when they're applied.
+    fn add_profiles(&mut self, value: &toml::Value, source: &Source) -> Result<(), ConfigError> {
+        let table = value.as_table().ok_or_else(|| {
+            ConfigError::new(source.clone(), Some("profiles"), "expected a table of profiles".into())
+        })?;
+        for (name, settings) in table {
+            let settings = settings.as_table().ok_or_else(|| {
+                ConfigError::new(source.clone(),
+                                 Some(&format!("profiles.{}", name)),
+                                 "expected a table of keys".into())
+            })?;
+            self.profiles.retain(|profile| &profile.name != name);
+            self.profiles.push(Profile {
+                name:       name.clone(),
+                defined_in: source.clone(),
+                settings:   settings.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Set every key in the profile called `name`.
+    pub fn apply_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        let profile = match self.profiles.iter().find(|profile| profile.name == name) {
+            Some(profile) => profile.clone(),
+            None          => {
+                return Err(ConfigError::new(Source::CommandLine,
+                                            Some("profile"),
+                                            format!("there's no profile named \"{}\"", name)));
+            },
+        };
+        let source = Source::Profile(profile.name.clone());
+        for (key, value) in &profile.settings {
+            self.set(key, value)
+                .map_err(|message| ConfigError::new(source.clone(), Some(key), message))?;
+            self.sources.insert(key.clone(), source.clone());
+        }
+        self.adapter_beats_force_warp(profile.settings.keys().map(|key| key.as_str()), &source);
+        Ok(())
+    }
+
+    /// Write each profile, where it's from, and what it sets.
+    ///
+    /// ```text
+    /// ci (built-in)
+    ///     force_warp = true
+    ///     ...
+    /// ```
+    pub fn write_profiles<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if i != 0 {
+                writeln!(w)?;
+            }
+            match profile.defined_in {
+                Source::Default => writeln!(w, "{} (built-in)", profile.name)?,
+                ref source      => writeln!(w, "{} ({})", profile.name, source)?,
+            }
+            for (key, value) in &profile.settings {
+                writeln!(w, "    {} = {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `key` was set from.
+    pub fn source(&self, key: &str) -> Source {
+        self.sources.get(key).cloned().unwrap_or(Source::Default)
+    }
+
+    /// The current value of `key`, or `None` if it's unset.
+    pub fn get(&self, key: &str) -> Option<toml::Value> {
+        use toml::Value;
+        let value = match key {
+            "force_warp"    => Value::Boolean(self.force_warp),
+            "adapter"       => Value::String(self.adapter.to_string()),
+            "fallback"      => Value::String(self.fallback.to_string()),
+            "enable_debug"  => Value::Boolean(self.enable_debug),
+            "debug_filter"  => Value::Array(self.debug_filter
+                                                .rules()
+                                                .into_iter()
+                                                .map(Value::String)
+                                                .collect()),
+            "debug_break"   => Value::String(self.debug_break?.to_string()),
+            "feature_level" => Value::String(self.feature_level.to_string()),
+            "fullscreen"    => Value::Boolean(self.fullscreen),
+            "resolution"    => Value::String(self.resolution.to_string()),
+            "vsync"         => Value::Boolean(self.vsync),
+            "clear_color"   => Value::String(self.clear_color.to_string()),
+            "overlays"      => Value::String(self.overlays.to_string()),
+            "color"         => Value::String(self.color.to_string()),
+            "error_theme"   => Value::String(self.error_theme.as_ref()?.display().to_string()),
+            "error_format"  => Value::String(self.error_format.to_string()),
+            "crash_report"  => Value::Boolean(self.crash_report),
+            "trace_api"     => Value::Boolean(self.trace_api),
+            _               => return None,
+        };
+        Some(value)
+    }
+
+    /// Write every key, its value, and where that value came from.
+    ///
+    /// ```text
+    /// force_warp    = true      # $DX12_FORCE_WARP
+    /// enable_debug  = true      # default
+    /// feature_level = "12_0"    # dx12.toml
+    /// ```
+    pub fn write_sources<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let key_width = KEYS.iter().map(|key| key.len()).max().unwrap_or(0);
+        let values: Vec<String> = KEYS.iter()
+            .map(|key| match self.get(key) {
+                Some(value) => value.to_string(),
+                None        => "<unset>".to_string(),
+            })
+            .collect();
+        let value_width = values.iter().map(|value| value.len()).max().unwrap_or(0);
+
+        for (key, value) in KEYS.iter().zip(values) {
+            writeln!(w, "{:<kw$} = {:<vw$}  # {}",
+                     key, value, self.source(key),
+                     kw = key_width,
+                     vw = value_width)?;
+        }
+        Ok(())
+    }
+
+    /// Write every key that's set as TOML that `apply_toml()` can read back.
+    ///
+    /// ```toml
+    /// force_warp = false
+    /// adapter = "0"
+    /// ...
+    /// ```
+    pub fn write_toml<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for key in KEYS {
+            if let Some(value) = self.get(key) {
+                writeln!(w, "{} = {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write every key as one JSON object, with `null` for unset keys.
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "{{")?;
+        for (i, key) in KEYS.iter().enumerate() {
+            if i != 0 {
+                write!(w, ",")?;
+            }
+            let value = match self.get(key) {
+                Some(ref value) => json_value(value),
+                None            => "null".to_string(),
+            };
+            write!(w, "{}:{}", json_string(key), value)?;
+        }
+        write!(w, "}}\n")
+    }
+
+    /// Set one field by name. Keys are the names of `Config`'s fields.
+    ///
+    /// Strings are accepted for every key, so values that didn't come from
+    /// TOML can be passed as `toml::Value::String`.
+    pub fn set(&mut self, key: &str, value: &toml::Value) -> Result<(), String> {
+        match key {
+            "force_warp"    => self.force_warp    = bool_value(value)?,
+            "adapter"       => self.adapter       = parse_value(value, "an adapter index, name, vendor, or LUID")?,
+            "fallback"      => self.fallback      = parse_value(value, "a list like \"adapters,levels,warp\"")?,
+            "enable_debug"  => self.enable_debug  = bool_value(value)?,
+            "debug_filter"  => self.debug_filter  = filter_value(value)?,
+            "debug_break"   => self.debug_break   = Some(parse_value(value, "a severity like \"error\"")?),
+            "feature_level" => self.feature_level = parse_value(value, "a feature level or range like \"11_0..12_1\"")?,
+            "fullscreen"    => self.fullscreen    = bool_value(value)?,
+            "resolution"    => self.resolution    = resolution_value(value)?,
+            "vsync"         => self.vsync         = bool_value(value)?,
+            "clear_color"   => self.clear_color   = parse_value(value, "a color like \"#336699\"")?,
+            "overlays"      => self.overlays      = parse_value(value, "a list like \"fps,adapter\"")?,
+            "color"         => self.color         = parse_value(value, "auto, always, or never")?,
+            "error_theme"   => self.error_theme   = Some(PathBuf::from(str_value(value)?)),
+            "error_format"  => self.error_format  = parse_value(value, "human or json")?,
+            "crash_report"  => self.crash_report  = bool_value(value)?,
+            "trace_api"     => self.trace_api     = bool_value(value)?,
+            _               => return Err("unknown key".into()),
+        }
+        Ok(())
+    }
+
+    /// Set everything given on the command line. Clap's defaults don't
+    /// count, so they don't override config files.
+    ///
+    /// Global options show up in `matches` wherever they were given.
+    fn apply_matches(&mut self, matches: &::clap::ArgMatches) {
+        let given = |name| matches.occurrences_of(name) > 0;
+
+        self.command = match matches.subcommand() {
+            ("hresult", Some(sub)) => {
+                Command::DecodeHresult(sub.value_of("value")
+                                          .expect("No HRESULT specified?")
+                                          // Clap verifies this:
+                                          .parse::<HResult>().unwrap())
+            },
+            ("adapters", _) => Command::ListAdapters,
+            ("caps", _)     => Command::ShowCaps,
+            ("config", Some(sub)) if sub.occurrences_of("dump") > 0 => {
+                Command::DumpConfig(sub.value_of("format")
+                                       // Clap verifies this:
+                                       .map(|format| format.parse::<DumpFormat>().unwrap())
+                                       .unwrap_or(DumpFormat::Toml))
+            },
+            ("config", Some(sub)) if sub.occurrences_of("profiles") > 0 => {
+                Command::ListProfiles
+            },
+            ("config", _)   => Command::ShowConfigSources,
+            _               => Command::Run,
+        };
+        let run_matches = run_matches(matches);
+
+        if given("force-warp") {
+            self.force_warp = true;
+        }
+        if let Some(adapter) = matches.value_of("adapter") {
+            // Clap verifies this:
+            self.adapter = adapter.parse::<AdapterSelector>().unwrap();
+            // Asking for an adapter beats a config file's `force_warp`.
+            self.force_warp = false;
+        }
+        if let Some(fallback) = matches.value_of("fallback") {
+            // Clap verifies this:
+            self.fallback = fallback.parse::<FallbackPolicy>().unwrap();
+        }
+        if given("debug") {
+            self.enable_debug = true;
+        }
+        if given("no-debug") {
+            self.enable_debug = false;
+        }
+        if let Some(rules) = matches.values_of("debug-filter") {
+            self.debug_filter = rules.collect::<Vec<_>>()
+                                     .join(";")
+                                     // Clap verifies this:
+                                     .parse::<MessageFilter>().unwrap();
+        }
+        if let Some(severity) = matches.value_of("debug-break") {
+            // Clap verifies this:
+            self.debug_break = Some(severity.parse::<MessageSeverity>().unwrap());
+        }
+        if given("feature-level") {
+            self.feature_level = matches.value_of("feature-level")
+                                        .expect("No feature level specified?")
+                                        // Clap verifies this:
+                                        .parse::<FeatureLevelRange>().unwrap();
+        }
+        if run_matches.occurrences_of("fullscreen") > 0 {
+            self.fullscreen = true;
+        }
+        if let Some(resolution) = run_matches.value_of("resolution") {
+            // Clap verifies this:
+            self.resolution = resolution.parse::<Resolution>().unwrap();
+        }
+        if let Some(width) = run_matches.value_of("window-width") {
+            // Clap verifies this:
+            self.resolution.width = parse_dimension(width).unwrap();
+        }
+        if let Some(height) = run_matches.value_of("window-height") {
+            // Clap verifies this:
+            self.resolution.height = parse_dimension(height).unwrap();
+        }
+        if run_matches.occurrences_of("no-vsync") > 0 {
+            self.vsync = false;
+        }
+        if let Some(color) = run_matches.value_of("clear-color") {
+            // Clap verifies this:
+            self.clear_color = color.parse::<ClearColor>().unwrap();
+        }
+        if let Some(overlays) = run_matches.value_of("overlays") {
+            // Clap verifies this:
+            self.overlays = overlays.parse::<Overlays>().unwrap();
+        }
+        if let Some(manifest) = run_matches.value_of_os("manifest") {
+            self.manifest = Some(PathBuf::from(manifest));
+        }
+        if given("color") {
+            self.color = matches.value_of("color")
+                                .expect("No color mode specified?")
+                                // Clap verifies this:
+                                .parse::<ColorMode>().unwrap();
+        }
+        if let Some(theme) = matches.value_of_os("error-theme") {
+            self.error_theme = Some(PathBuf::from(theme));
+        }
+        if given("error-format") {
+            self.error_format = matches.value_of("error-format")
+                                       .expect("No error format specified?")
+                                       // Clap verifies this:
+                                       .parse::<ErrorFormat>().unwrap();
+        }
+        if given("no-crash-report") {
+            self.crash_report = false;
+        }
+        if given("trace-api") {
+            self.trace_api = true;
+        }
+
+        for key in KEYS {
+            if cli_sets(matches, key) {
+                self.sources.insert(key.to_string(), Source::CommandLine);
+            }
+        }
+    }
+}
+
+/// Whether the command line sets `key`.
+fn cli_sets(matches: &::clap::ArgMatches, key: &str) -> bool {
+    let args: &[&str] = match key {
+        "force_warp"    => &["force-warp", "adapter"],
+        "adapter"       => &["adapter"],
+        "fallback"      => &["fallback"],
+        "enable_debug"  => &["debug", "no-debug"],
+        "debug_filter"  => &["debug-filter"],
+        "debug_break"   => &["debug-break"],
+        "feature_level" => &["feature-level"],
+        "fullscreen"    => &["fullscreen"],
+        "resolution"    => &["resolution", "window-width", "window-height"],
+        "vsync"         => &["no-vsync"],
+        "clear_color"   => &["clear-color"],
+        "overlays"      => &["overlays"],
+        "color"         => &["color"],
+        "error_theme"   => &["error-theme"],
+        "error_format"  => &["error-format"],
+        "crash_report"  => &["no-crash-report"],
+        "trace_api"     => &["trace-api"],
+        _               => &[],
+    };
+    let run_matches = run_matches(matches);
+    args.iter().any(|arg| {
+        matches.occurrences_of(arg) > 0 || run_matches.occurrences_of(arg) > 0
+    })
+}
+
+/// Where `run_args()` were given: to `run`, or to the top level when `run`
+/// is implied.
+fn run_matches<'m, 'a>(matches: &'m ::clap::ArgMatches<'a>) -> &'m ::clap::ArgMatches<'a> {
+    matches.subcommand_matches("run").unwrap_or(matches)
+}
+
+/// Config files to load, lowest precedence first.
+pub fn config_file_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if let Some(dir) = ::dirs::config_dir() {
+        paths.push(dir.join("dx12").join(CONFIG_FILE_NAME));
+    }
+    paths.push(PathBuf::from(CONFIG_FILE_NAME));
+    paths
+}
+
+/// The `KEYS` entry for an environment variable like `DX12_FORCE_WARP`.
+fn env_key(name: &str) -> Option<&'static str> {
+    if !name.starts_with("DX12_") {
+        return None;
+    }
+    let name = &name["DX12_".len()..];
+    KEYS.iter().cloned().find(|key| key.eq_ignore_ascii_case(name))
+}
+
+/// Where a config value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    /// The name of the environment variable.
+    Env(String),
+    /// The name of the profile.
+    Profile(String),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default           => write!(f, "default"),
+            Source::File(ref path)    => write!(f, "{}", path.display()),
+            Source::Env(ref name)     => write!(f, "${}", name),
+            Source::Profile(ref name) => write!(f, "profile {}", name),
+            Source::CommandLine       => write!(f, "command line"),
+        }
+    }
+}
+
+/// A bad config file, or a bad value in one.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub source:  Source,
+    pub key:     Option<String>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(source: Source, key: Option<&str>, message: String) -> ConfigError {
+        ConfigError {
+            source,
+            key:     key.map(|key| key.to_string()),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    /// e.g. `dx12.toml: feature_level: "13" is not a feature level or range like "11_0..12_1"`
+    /// or `$DX12_FEATURE_LEVEL: "13" is not a feature level or range like "11_0..12_1"`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)?;
+        match (&self.source, &self.key) {
+            // The variable's name already says which key it is.
+            (&Source::Env(_), _) => {},
+            (_, &Some(ref key))  => write!(f, ": {}", key)?,
+            (_, &None)           => {},
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+fn bool_value(value: &toml::Value) -> Result<bool, String> {
+    match *value {
+        toml::Value::Boolean(b) => Ok(b),
+        toml::Value::String(ref s) => match s.to_lowercase().as_str() {
+            "1" | "true"  | "yes" | "on"  => Ok(true),
+            "0" | "false" | "no"  | "off" => Ok(false),
+            _ => Err(format!("\"{}\" is not true or false", s)),
+        },
+        _ => Err(format!("expected true or false, not {}", value.type_str())),
+    }
+}
+
+fn str_value(value: &toml::Value) -> Result<&str, String> {
+    value.as_str().ok_or_else(|| format!("expected a string, not {}", value.type_str()))
+}
+
+/// Parse strings, and integers as if they were strings.
+fn parse_value<T: FromStr>(value: &toml::Value, expected: &str) -> Result<T, String> {
+    let string = match *value {
+        toml::Value::String(ref s)  => s.clone(),
+        toml::Value::Integer(i)     => i.to_string(),
+        _ => return Err(format!("expected {}, not {}", expected, value.type_str())),
+    };
+    string.parse().map_err(|_| format!("\"{}\" is not {}", string, expected))
+}
+
+fn resolution_value(value: &toml::Value) -> Result<Resolution, String> {
+    let string = str_value(value)?;
+    string.parse().map_err(|err| format!("\"{}\" is not a resolution: {}", string, err))
+}
+
+/// Parse a whole TOML file that was read from `path`.
+fn parse_toml(text: &str, path: &Path) -> Result<toml::Value, ConfigError> {
+    // TOML's errors already say which line they're on.
+    text.parse::<toml::Value>()
+        .map_err(|err| ConfigError::new(Source::File(path.to_path_buf()), None, err.to_string()))
+}
+
+fn json_value(value: &toml::Value) -> String {
+    use toml::Value;
+    match *value {
+        Value::String(ref s)   => json_string(s),
+        Value::Integer(i)      => i.to_string(),
+        Value::Float(f)        => f.to_string(),
+        Value::Boolean(b)      => b.to_string(),
+        Value::Datetime(ref d) => json_string(&d.to_string()),
+        Value::Array(ref values) => {
+            let values: Vec<String> = values.iter().map(json_value).collect();
+            format!("[{}]", values.join(","))
+        },
+        Value::Table(ref table) => {
+            let pairs: Vec<String> = table.iter()
+                .map(|(key, value)| format!("{}:{}", json_string(key), json_value(value)))
+                .collect();
+            format!("{{{}}}", pairs.join(","))
+        },
+    }
+}
+
+/// A named set of keys, applied with `--profile NAME`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name:       String,
+    /// `Source::Default` for built-ins, otherwise the file.
+    pub defined_in: Source,
+    pub settings:   toml::value::Table,
+}
+
+fn builtin_profiles() -> Vec<Profile> {
+    let table = match BUILTIN_PROFILES.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        _                             => panic!("BUILTIN_PROFILES isn't a table of tables"),
+    };
+    table.into_iter()
+        .map(|(name, settings)| {
+            let settings = match settings {
+                toml::Value::Table(settings) => settings,
+                _                            => panic!("built-in profile {} isn't a table", name),
+            };
+            Profile { name, defined_in: Source::Default, settings }
+        })
+        .collect()
+}
+
+/// Either a list of rules, or one string of them separated by `;`.
+fn filter_value(value: &toml::Value) -> Result<MessageFilter, String> {
+    let rules = match *value {
+        toml::Value::String(ref s) => s.clone(),
+        toml::Value::Array(ref rules) => {
+            let rules: Result<Vec<&str>, String> = rules.iter().map(str_value).collect();
+            rules?.join(";")
+        },
+        _ => return Err(format!("expected a list of rules, not {}", value.type_str())),
+    };
+    rules.parse().map_err(|_| format!("\"{}\" is not a rule like \"deny:id=820\"", rules))
+}
+
+/// What we were asked to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Open a window and draw things. This is the default.
+    Run,
+    /// Print everything we know about an `HRESULT` and exit.
+    DecodeHresult(HResult),
+    /// Print every config value and where it came from, and exit.
+    ShowConfigSources,
+    /// Print every config value so it can be read back, and exit.
+    DumpConfig(DumpFormat),
+    /// Print every profile, and what it sets, and exit.
+    ListProfiles,
+    /// Print every adapter, and which one we'd pick, and exit.
+    ListAdapters,
+    /// Create a device, print what it supports, and exit.
+    ShowCaps,
+}
+
+/// When to color diagnostic output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Color when stderr is a terminal and `NO_COLOR` isn't set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ColorMode::Auto   => write!(f, "auto"),
+            ColorMode::Always => write!(f, "always"),
+            ColorMode::Never  => write!(f, "never"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidColorMode;
+
+impl ::std::str::FromStr for ColorMode {
+    type Err = InvalidColorMode;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "auto"   => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never"  => Ok(ColorMode::Never),
+            _        => Err(InvalidColorMode),
+        }
+    }
+}
+
+/// How errors are reported on stderr.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// Colored, multi-line messages for people.
+    Human,
+    /// One JSON object per line, for scripts.
+    Json,
+}
+
+impl fmt::Display for ErrorFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorFormat::Human => write!(f, "human"),
+            ErrorFormat::Json  => write!(f, "json"),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct InvalidErrorFormat;
+
+impl ::std::str::FromStr for ErrorFormat {
+    type Err = InvalidErrorFormat;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "human" => Ok(ErrorFormat::Human),
+            "json"  => Ok(ErrorFormat::Json),
+            _       => Err(InvalidErrorFormat),
+        }
+    }
+}
+
+/// How `config --dump` writes the config.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DumpFormat {
+    Toml,
+    Json,
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DumpFormat::Toml => write!(f, "toml"),
+            DumpFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidDumpFormat;
+
+impl ::std::str::FromStr for DumpFormat {
+    type Err = InvalidDumpFormat;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "toml" => Ok(DumpFormat::Toml),
+            "json" => Ok(DumpFormat::Json),
+            _      => Err(InvalidDumpFormat),
+        }
+    }
+}
+
+/// The size of the window's client area, and of the swapchain's buffers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    pub width:  u32,
+    pub height: u32,
+}
+
+/// The largest width or height we'll accept, since our backbuffers can't be
+/// any bigger.
+pub const MAX_DIMENSION: u32 = ::winapi::um::d3d12::D3D12_REQ_TEXTURE2D_U_OR_V_DIMENSION;
+
+impl Default for Resolution {
+    fn default() -> Resolution {
+        Resolution { width: 1024, height: 1024 }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InvalidResolution {
+    /// Not `WxH`.
+    Syntax,
+    NotANumber,
+    Zero,
+    TooLarge,
+}
+
+impl fmt::Display for InvalidResolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidResolution::Syntax     => write!(f, "expected WIDTHxHEIGHT, like 1920x1080"),
+            InvalidResolution::NotANumber => write!(f, "sizes are whole numbers of pixels"),
+            InvalidResolution::Zero       => write!(f, "sizes can't be zero"),
+            InvalidResolution::TooLarge   => write!(f, "sizes can't be larger than {}, \
+                                                        the largest D3D12 texture",
+                                                    MAX_DIMENSION),
+        }
+    }
+}
+
+impl ::std::str::FromStr for Resolution {
+    type Err = InvalidResolution;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut sizes = string.splitn(2, |c| c == 'x' || c == 'X');
+        match (sizes.next(), sizes.next()) {
+            (Some(width), Some(height)) => Ok(Resolution {
+                width:  parse_dimension(width)?,
+                height: parse_dimension(height)?,
+            }),
+            _ => Err(InvalidResolution::Syntax),
+        }
+    }
+}
+
+/// What we clear the back buffer to before drawing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClearColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl ClearColor {
+    /// What `ClearRenderTargetView` wants.
+    pub fn to_f32s(&self) -> [f32; 4] {
+        [self.r as f32 / 255.0,
+         self.g as f32 / 255.0,
+         self.b as f32 / 255.0,
+         self.a as f32 / 255.0]
+    }
+}
+
+impl Default for ClearColor {
+    /// The dark blue from Microsoft's D3D12 samples.
+    fn default() -> ClearColor {
+        ClearColor { r: 0x00, g: 0x33, b: 0x66, a: 0xff }
+    }
+}
+
+impl fmt::Display for ClearColor {
+    /// `#rrggbb`, or `#rrggbbaa` if it isn't opaque.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        if self.a != 0xff {
+            write!(f, "{:02x}", self.a)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidClearColor;
+
+impl ::std::str::FromStr for ClearColor {
+    type Err = InvalidClearColor;
+
+    /// `#rrggbb` or `#rrggbbaa`. The `#` is optional.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let string = string.trim();
+        let hex = if string.starts_with('#') { &string[1..] } else { string };
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_digit(16)) {
+            return Err(InvalidClearColor);
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(ClearColor {
+            r: channel(0),
+            g: channel(2),
+            b: channel(4),
+            a: if hex.len() == 8 { channel(6) } else { 0xff },
+        })
+    }
+}
+
+/// Parse one side of a `Resolution`.
+pub fn parse_dimension(string: &str) -> Result<u32, InvalidResolution> {
+    let string = string.trim();
+    let size = match string.parse::<u32>() {
+        Ok(size) => size,
+        // All digits, but too many of them for a u32.
+        Err(_) if !string.is_empty() && string.chars().all(|c| c.is_digit(10)) => {
+            return Err(InvalidResolution::TooLarge);
+        },
+        Err(_) => return Err(InvalidResolution::NotANumber),
+    };
+    match size {
+        0                            => Err(InvalidResolution::Zero),
+        size if size > MAX_DIMENSION => Err(InvalidResolution::TooLarge),
+        size                         => Ok(size),
+    }
+}
+
+/// A `D3D_FEATURE_LEVEL`. Later levels compare greater.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Dx12FeatureLevel {
+    /// Compute only, for MCDM adapters.
+    E1_0Core,
+    E9_1,
+    E9_2,
+    E9_3,
+    E10_0,
+    E10_1,
     E11_0,
     E11_1,
     E12_0,
     E12_1,
+    E12_2,
+}
+
+impl Dx12FeatureLevel {
+    /// Every level, lowest first.
+    pub fn all() -> &'static [Dx12FeatureLevel] {
+        &[
+            Dx12FeatureLevel::E1_0Core,
+            Dx12FeatureLevel::E9_1,
+            Dx12FeatureLevel::E9_2,
+            Dx12FeatureLevel::E9_3,
+            Dx12FeatureLevel::E10_0,
+            Dx12FeatureLevel::E10_1,
+            Dx12FeatureLevel::E11_0,
+            Dx12FeatureLevel::E11_1,
+            Dx12FeatureLevel::E12_0,
+            Dx12FeatureLevel::E12_1,
+            Dx12FeatureLevel::E12_2,
+        ]
+    }
+
+    /// The `D3D_FEATURE_LEVEL_*` value. Not every level we know is in the
+    /// winapi we build against, so these are spelled out.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Dx12FeatureLevel::E1_0Core => 0x1000,
+            Dx12FeatureLevel::E9_1     => 0x9100,
+            Dx12FeatureLevel::E9_2     => 0x9200,
+            Dx12FeatureLevel::E9_3     => 0x9300,
+            Dx12FeatureLevel::E10_0    => 0xa000,
+            Dx12FeatureLevel::E10_1    => 0xa100,
+            Dx12FeatureLevel::E11_0    => 0xb000,
+            Dx12FeatureLevel::E11_1    => 0xb100,
+            Dx12FeatureLevel::E12_0    => 0xc000,
+            Dx12FeatureLevel::E12_1    => 0xc100,
+            Dx12FeatureLevel::E12_2    => 0xc200,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Option<Dx12FeatureLevel> {
+        Dx12FeatureLevel::all().iter().cloned().find(|level| level.to_u32() == value)
+    }
+
+    /// Whether `D3D12CreateDevice` accepts this as a minimum. 9_x and 10_x
+    /// are only good for asking what a device supports.
+    pub fn can_create_device(self) -> bool {
+        self == Dx12FeatureLevel::E1_0Core || self >= Dx12FeatureLevel::E11_0
+    }
+}
+
+impl fmt::Display for Dx12FeatureLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Dx12FeatureLevel::E1_0Core => write!(f, "1_0_core"),
+            Dx12FeatureLevel::E9_1     => write!(f, "9_1"),
+            Dx12FeatureLevel::E9_2     => write!(f, "9_2"),
+            Dx12FeatureLevel::E9_3     => write!(f, "9_3"),
+            Dx12FeatureLevel::E10_0    => write!(f, "10_0"),
+            Dx12FeatureLevel::E10_1    => write!(f, "10_1"),
+            Dx12FeatureLevel::E11_0    => write!(f, "11_0"),
+            Dx12FeatureLevel::E11_1    => write!(f, "11_1"),
+            Dx12FeatureLevel::E12_0    => write!(f, "12_0"),
+            Dx12FeatureLevel::E12_1    => write!(f, "12_1"),
+            Dx12FeatureLevel::E12_2    => write!(f, "12_2"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -43,37 +1167,130 @@ pub struct InvalidFeatureLevel;
 impl ::std::str::FromStr for Dx12FeatureLevel {
     type Err = InvalidFeatureLevel;
 
+    /// What `Display` writes, with `.` for `_` if you like, and a missing
+    /// minor version meaning 0, e.g. `12`, `12.1`, `12_1`, or `1.0_core`.
     fn from_str(string: &str) -> Result<Self, Self::Err> {
-        match string {
-            "11" | "11.0" | "11_0" => Ok(Dx12FeatureLevel::E11_0),
-                   "11.1" | "11_1" => Ok(Dx12FeatureLevel::E11_1),
-            "12" | "12.0" | "12_0" => Ok(Dx12FeatureLevel::E12_0),
-                   "12.1" | "12_1" => Ok(Dx12FeatureLevel::E12_1),
-            _                      => Err(InvalidFeatureLevel),
-        }
+        let normal = string.trim().to_lowercase().replace('.', "_");
+        Dx12FeatureLevel::all()
+            .iter()
+            .cloned()
+            .find(|level| {
+                let name = level.to_string();
+                normal == name || format!("{}_0", normal) == name
+            })
+            .ok_or(InvalidFeatureLevel)
     }
 }
 
 impl Into<u32> for Dx12FeatureLevel {
     fn into(self) -> u32 {
-        use winapi::um::d3dcommon;
-        match self {
-            Dx12FeatureLevel::E11_0 => d3dcommon::D3D_FEATURE_LEVEL_11_0,
-            Dx12FeatureLevel::E11_1 => d3dcommon::D3D_FEATURE_LEVEL_11_1,
-            Dx12FeatureLevel::E12_0 => d3dcommon::D3D_FEATURE_LEVEL_12_0,
-            Dx12FeatureLevel::E12_1 => d3dcommon::D3D_FEATURE_LEVEL_12_1,
+        self.to_u32()
+    }
+}
+
+/// The feature levels we'll accept, from `--feature-level min..max`.
+///
+/// We create the device at `min`, then ask which of the levels in between it
+/// supports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FeatureLevelRange {
+    pub min: Dx12FeatureLevel,
+    pub max: Dx12FeatureLevel,
+}
+
+impl FeatureLevelRange {
+    /// Every level from `min` to `max`, lowest first.
+    pub fn levels(&self) -> Vec<Dx12FeatureLevel> {
+        Dx12FeatureLevel::all()
+            .iter()
+            .cloned()
+            .filter(|&level| self.min <= level && level <= self.max)
+            .collect()
+    }
+
+    /// The minimum to give `D3D12CreateDevice`, which won't take 9_x or 10_x.
+    pub fn device_min(&self) -> Dx12FeatureLevel {
+        if self.min.can_create_device() {
+            self.min
+        } else {
+            Dx12FeatureLevel::E11_0
+        }
+    }
+}
+
+impl Default for FeatureLevelRange {
+    fn default() -> FeatureLevelRange {
+        FeatureLevelRange {
+            min: Dx12FeatureLevel::E11_0,
+            max: Dx12FeatureLevel::E12_2,
+        }
+    }
+}
+
+impl fmt::Display for FeatureLevelRange {
+    /// `min..max`, or just `min` when they're the same.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}..{}", self.min, self.max)
+        }
+    }
+}
+
+impl ::std::str::FromStr for FeatureLevelRange {
+    type Err = InvalidFeatureLevel;
+
+    /// `min..max`, where either end can be left off to mean the lowest or
+    /// highest level, or one level on its own for exactly that level.
+    ///
+    /// The lowest is 9_1: 1_0_core is compute only, so you have to ask for it.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let all = Dx12FeatureLevel::all();
+        let (min, max) = match string.find("..") {
+            Some(dots) => (&string[..dots], &string[dots + "..".len()..]),
+            None       => (string, string),
+        };
+        let min = match min.trim() {
+            ""  => Dx12FeatureLevel::E9_1,
+            min => min.parse()?,
+        };
+        let max = match max.trim() {
+            ""  => all[all.len() - 1],
+            max => max.parse()?,
+        };
+        if min > max {
+            return Err(InvalidFeatureLevel);
         }
+        Ok(FeatureLevelRange { min, max })
     }
 }
 
+/// Parse our args, exiting with `Category::InvalidConfig`'s code if they're bad.
 fn get_arg_matches<'a>() -> ::clap::ArgMatches<'a> {
-    use clap::{App, AppSettings, Arg};
+    match build_app().get_matches_safe() {
+        Ok(matches) => matches,
+        // --help and friends come through here too.
+        Err(ref err) if !err.use_stderr() => {
+            println!("{}", err.message);
+            process::exit(0);
+        },
+        Err(err) => {
+            eprintln!("{}", err.message);
+            process::exit(Category::InvalidConfig.exit_code());
+        },
+    }
+}
+
+fn build_app<'a, 'b>() -> ::clap::App<'a, 'b> {
+    use clap::{App, AppSettings, Arg, SubCommand};
     App
         // Metadata
         ::new("Dx12 Demo")
         .about("Draw things with DX12")
         .setting(AppSettings::DisableVersion)
-        .setting(AppSettings::UnifiedHelpMessage)
+        .global_setting(AppSettings::UnifiedHelpMessage)
+        .global_setting(AppSettings::VersionlessSubcommands)
 
         // The default "--help" Arg has a display order of 999,
         // So we start ours at 3000.
@@ -81,54 +1298,456 @@ fn get_arg_matches<'a>() -> ::clap::ArgMatches<'a> {
         // Adapter selection
         .arg(Arg::with_name("force-warp")
                 .display_order(3000)
+                .global(true)
                 .help("Force using the warp adapter")
                 .long("force-warp")
                 .short("w")
                 .required(false)
                 .overrides_with("adapter"))
-        // TODO: Some way to select an adapter
+        .arg(Arg::with_name("adapter")
+                .display_order(3001)
+                .global(true)
+                .help("Select an adapter by index, name, vendor, or LUID, \
+                       or with max-vram or integrated [default: 0]")
+                .long("adapter")
+                .short("a")
+                .takes_value(true)
+                .overrides_with("force-warp")
+                .validator(|value| {
+                    value.parse::<AdapterSelector>()
+                         .map(|_| ())
+                         .map_err(|_| format!("\"{}\" is not an adapter index, name, \
+                                               vendor, or LUID", value))
+                }))
+        .arg(Arg::with_name("fallback")
+                .display_order(3002)
+                .global(true)
+                .help("What to try when we can't create the device we asked for: other adapters, \
+                       lower feature levels, and WARP, or none [default: adapters,levels,warp]")
+                .long("fallback")
+                .value_name("STEPS")
+                .validator(|value| {
+                    value.parse::<FallbackPolicy>()
+                         .map(|_| ())
+                         .map_err(|_| format!("\"{}\" is not \"none\" or a list like \
+                                               \"adapters,levels,warp\"", value))
+                }))
 
         // Debug options
         .arg(Arg::with_name("debug")
                 .display_order(3100)
+                .global(true)
                 .help("Enable the DX12 runtime debug layer")
                 .long("debug-layer")
                 .overrides_with("no-debug-layer"))
         .arg(Arg::with_name("no-debug")
                 .display_order(3101)
+                .global(true)
                 .help("Disable the DX12 runtime debug layer")
                 .long("no-debug-layer")
                 .overrides_with("debug-layer"))
+        .arg(Arg::with_name("debug-filter")
+                .display_order(3102)
+                .global(true)
+                .help("Only print some debug layer messages, \
+                       e.g. \"deny:id=820,1008\" or \"allow:severity=error,warning\"")
+                .long("debug-filter")
+                .value_name("RULE")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|rule| {
+                    rule.parse::<MessageFilter>()
+                        .map(|_| ())
+                        .map_err(|_| format!("\"{}\" is not a rule like \"deny:id=820\"; \
+                                              use allow: or deny: with \
+                                              severity=, category=, or id=",
+                                             rule))
+                }))
+        .arg(Arg::with_name("debug-break")
+                .display_order(3103)
+                .global(true)
+                .help("Break into the debugger on debug layer messages this severe")
+                .long("debug-break")
+                .possible_values(&["corruption", "error", "warning"])
+                .takes_value(true))
 
         // I change this enough to just make it an option.
         .arg(Arg::with_name("feature-level")
                 .display_order(3200)
-                .help("Force using a specific feature level for CreateDevice")
+                .global(true)
+                .help("Create the device at MIN, and report the highest level it supports up to MAX. \
+                       A single level means exactly that one. [default: 11_0..12_2]")
                 .long("feature-level")
-                .possible_values(&["11", "11.0", "11_0",
-                                         "11.1", "11_1",
-                                   "12", "12.0", "12_0",
-                                         "12.1", "12_1"])
-                .default_value("11_0"))
-
-        // Resolution themed options
-        .arg(Arg::with_name("fullscreen")
+                .value_name("MIN..MAX")
+                .validator(|value| {
+                    value.parse::<FeatureLevelRange>()
+                         .map(|_| ())
+                         .map_err(|_| format!("\"{}\" is not a feature level like 12_0, \
+                                               or a range like 11_0..12_1", value))
+                }))
+
+        // Options for the implied `run`. Only `run`'s help lists them.
+        .args(&run_args(true))
+
+        // Diagnostic output
+        .arg(Arg::with_name("color")
+                .display_order(3600)
+                .global(true)
+                .help("When to color error output")
+                .long("color")
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto"))
+        .arg(Arg::with_name("error-theme")
+                .display_order(3601)
+                .global(true)
+                .help("Load error output colors from a theme file")
+                .long("error-theme")
+                .value_name("FILE")
+                .takes_value(true))
+        .arg(Arg::with_name("error-format")
+                .display_order(3602)
+                .global(true)
+                .help("Report errors as colored text or one JSON object per line")
+                .long("error-format")
+                .possible_values(&["human", "json"])
+                .default_value("human"))
+        .arg(Arg::with_name("no-crash-report")
+                .display_order(3603)
+                .global(true)
+                .help("Don't write a dx12-crash-*.txt report when we fail or panic")
+                .long("no-crash-report"))
+        .arg(Arg::with_name("trace-api")
+                .display_order(3604)
+                .global(true)
+                .help("Time every D3D12/DXGI call and print a summary at exit")
+                .long("trace-api"))
+
+        // Config
+        .arg(Arg::with_name("replay-config")
+                .display_order(3701)
+                .global(true)
+                .help("Load the config from a manifest or a config --dump, \
+                       overriding config files and the environment")
+                .long("replay-config")
+                .value_name("FILE")
+                .takes_value(true))
+        .arg(Arg::with_name("profile")
+                .display_order(3702)
+                .global(true)
+                .help("Apply a named profile from a config file, or ci, perf, or debug. \
+                       Flags still override it. Can be given more than once.")
+                .long("profile")
+                .value_name("NAME")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+
+        // Subcommands
+        .subcommand(SubCommand::with_name("run")
+                .about("Open a window and draw things. This is the default.")
+                .args(&run_args(false)))
+        .subcommand(SubCommand::with_name("adapters")
+                .about("List adapters, and which one we'd use, without creating a device"))
+        .subcommand(SubCommand::with_name("caps")
+                .about("Print what the selected adapter's device supports, without a window"))
+        .subcommand(SubCommand::with_name("config")
+                .about("Print each config value and where it came from")
+                .arg(Arg::with_name("dump")
+                        .help("Print the config so it can be read back, instead of \
+                               where it came from")
+                        .long("dump"))
+                .arg(Arg::with_name("profiles")
+                        .help("List the profiles --profile can apply, and what they set")
+                        .long("profiles")
+                        .conflicts_with("dump"))
+                .arg(Arg::with_name("format")
+                        // A default value would count as giving it, and trip
+                        // `requires`.
+                        .help("How --dump prints the config [default: toml]")
+                        .long("format")
+                        .takes_value(true)
+                        .requires("dump")
+                        .possible_values(&["toml", "json"])))
+        .subcommand(SubCommand::with_name("hresult")
+                .about("Decode an HRESULT without creating a window or device")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(Arg::with_name("value")
+                        .help("e.g. 0x887A0005, 2289696773, or -2005270523")
+                        .required(true)
+                        .validator(|value| {
+                            value.parse::<HResult>()
+                                 .map(|_| ())
+                                 .map_err(|_| format!("\"{}\" is not a hex or decimal HRESULT",
+                                                      value))
+                        })))
+
+        // End
+}
+
+/// Options for `run`, mostly about the window we draw to. Both the top
+/// level, for the implied `run`, and `run` itself take these.
+fn run_args<'a, 'b>(hidden: bool) -> Vec<::clap::Arg<'a, 'b>> {
+    use clap::Arg;
+    vec![
+        Arg::with_name("fullscreen")
                 .display_order(3300)
+                .hidden(hidden)
                 .help("Create a fullscreen swapchain")
                 .long("fullscreen")
+                .conflicts_with("resolution")
+                .conflicts_with("window-width")
+                .conflicts_with("window-height"),
+        Arg::with_name("resolution")
+                .display_order(3301)
+                .hidden(hidden)
+                .help("Set the window and swapchain size [default: 1024x1024]")
+                .long("resolution")
+                .value_name("WxH")
                 .conflicts_with("window-width")
-                .conflicts_with("window-height"))
-        .arg(Arg::with_name("window-width")
+                .conflicts_with("window-height")
+                .validator(|value| {
+                    value.parse::<Resolution>()
+                         .map(|_| ())
+                         .map_err(|err| format!("\"{}\" is not a resolution: {}", value, err))
+                }),
+        Arg::with_name("window-width")
                 .display_order(3400)
-                .help("Set the application window width. Invalid numbers default to 0.")
+                .hidden(hidden)
+                .help("Set the window and swapchain width")
                 .long("width")
-                .takes_value(true))
-        .arg(Arg::with_name("window-height")
+                .takes_value(true)
+                .validator(|value| {
+                    parse_dimension(&value)
+                        .map(|_| ())
+                        .map_err(|err| format!("\"{}\" is not a width: {}", value, err))
+                }),
+        Arg::with_name("window-height")
                 .display_order(3500)
-                .help("Set the application window height. Invalid numbers default to 0.")
+                .hidden(hidden)
+                .help("Set the window and swapchain height")
                 .long("height")
-                .takes_value(true))
+                .takes_value(true)
+                .validator(|value| {
+                    parse_dimension(&value)
+                        .map(|_| ())
+                        .map_err(|err| format!("\"{}\" is not a height: {}", value, err))
+                }),
+        Arg::with_name("no-vsync")
+                .display_order(3501)
+                .hidden(hidden)
+                .help("Present as fast as we can, instead of waiting for vertical blank")
+                .long("no-vsync"),
+        Arg::with_name("clear-color")
+                .display_order(3502)
+                .hidden(hidden)
+                .help("Clear the window to this color [default: #003366]")
+                .long("clear-color")
+                .value_name("#RRGGBB")
+                .takes_value(true)
+                .validator(|value| {
+                    value.parse::<ClearColor>()
+                         .map(|_| ())
+                         .map_err(|_| format!("\"{}\" is not a color like #336699 \
+                                               or #33669980", value))
+                }),
+        Arg::with_name("overlays")
+                .display_order(3503)
+                .hidden(hidden)
+                .help("Show fps, adapter, or both in the window title, or none [default: none]")
+                .long("overlays")
+                .value_name("LIST")
+                .takes_value(true)
+                .validator(|value| {
+                    value.parse::<Overlays>()
+                         .map(|_| ())
+                         .map_err(|_| format!("\"{}\" is not \"none\" or a list like \
+                                               \"fps,adapter\"", value))
+                }),
+        Arg::with_name("manifest")
+                .display_order(3700)
+                .hidden(hidden)
+                .help("Write the config, adapter, and swapchain we used to a TOML file \
+                       that --replay-config can read")
+                .long("manifest")
+                .value_name("FILE")
+                .takes_value(true),
+    ]
+}
 
-        // End
-        .get_matches()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config file that forces WARP.
+    fn warp_config() -> Config {
+        let mut config = Config::default();
+        config.apply_toml("force_warp = true\n", Path::new("dx12.toml")).unwrap();
+        assert!(config.force_warp);
+        config
+    }
+
+    #[test]
+    fn adapter_from_the_environment_beats_force_warp() {
+        let mut config = warp_config();
+        config.apply_env(vec![(OsString::from("DX12_ADAPTER"), OsString::from("1"))]).unwrap();
+        assert!(!config.force_warp);
+        assert_eq!(config.adapter.to_string(), "1");
+        assert_eq!(config.source("force_warp"), Source::Env("DX12_ADAPTER".into()));
+    }
+
+    #[test]
+    fn adapter_from_a_later_file_beats_force_warp() {
+        let mut config = warp_config();
+        config.apply_toml("adapter = \"nvidia\"\n", Path::new("local.toml")).unwrap();
+        assert!(!config.force_warp);
+        assert_eq!(config.source("force_warp"), Source::File(PathBuf::from("local.toml")));
+    }
+
+    #[test]
+    fn adapter_from_a_profile_beats_force_warp() {
+        let mut config = warp_config();
+        config.apply_toml("[profiles.gpu]\nadapter = \"0\"\n", Path::new("profiles.toml")).unwrap();
+        assert!(config.force_warp);
+        config.apply_profile("gpu").unwrap();
+        assert!(!config.force_warp);
+        assert_eq!(config.source("force_warp"), Source::Profile("gpu".into()));
+    }
+
+    #[test]
+    fn a_layer_that_sets_both_keeps_its_force_warp() {
+        let mut config = Config::default();
+        config.apply_toml("adapter = \"1\"\nforce_warp = true\n", Path::new("dx12.toml")).unwrap();
+        assert!(config.force_warp);
+
+        let mut config = Config::default();
+        config.apply_env(vec![(OsString::from("DX12_FORCE_WARP"), OsString::from("true")),
+                              (OsString::from("DX12_ADAPTER"),    OsString::from("1"))])
+            .unwrap();
+        assert!(config.force_warp);
+    }
+
+    #[test]
+    fn other_keys_leave_force_warp_alone() {
+        let mut config = warp_config();
+        config.apply_env(vec![(OsString::from("DX12_VSYNC"), OsString::from("false"))]).unwrap();
+        assert!(config.force_warp);
+        assert_eq!(config.source("force_warp"), Source::File(PathBuf::from("dx12.toml")));
+    }
+
+    fn range(min: Dx12FeatureLevel, max: Dx12FeatureLevel) -> FeatureLevelRange {
+        FeatureLevelRange { min, max }
+    }
+
+    #[test]
+    fn parses_feature_level_ranges() {
+        use self::Dx12FeatureLevel::*;
+        assert_eq!("11_0..12_1".parse::<FeatureLevelRange>().unwrap(), range(E11_0, E12_1));
+        assert_eq!("12".parse::<FeatureLevelRange>().unwrap(), range(E12_0, E12_0));
+        assert_eq!("12_0..".parse::<FeatureLevelRange>().unwrap(), range(E12_0, E12_2));
+        assert_eq!("1_0_core..".parse::<FeatureLevelRange>().unwrap(), range(E1_0Core, E12_2));
+        assert!("12_1..11_0".parse::<FeatureLevelRange>().is_err());
+        assert!("13".parse::<FeatureLevelRange>().is_err());
+    }
+
+    #[test]
+    fn open_lower_bound_leaves_out_1_0_core() {
+        use self::Dx12FeatureLevel::*;
+        let open = "..12_1".parse::<FeatureLevelRange>().unwrap();
+        assert_eq!(open, range(E9_1, E12_1));
+        assert!(!open.levels().contains(&E1_0Core));
+        assert_eq!(open.device_min(), E11_0);
+        assert_eq!("..".parse::<FeatureLevelRange>().unwrap(), range(E9_1, E12_2));
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones() {
+        let mut config = Config::default();
+        config.apply_toml("vsync = false\nresolution = \"800x600\"\n", Path::new("dx12.toml"))
+            .unwrap();
+        config.apply_toml("resolution = \"1920x1080\"\n", Path::new("local.toml")).unwrap();
+
+        assert!(!config.vsync);
+        assert_eq!(config.source("vsync"), Source::File(PathBuf::from("dx12.toml")));
+        assert_eq!(config.resolution, Resolution { width: 1920, height: 1080 });
+        assert_eq!(config.source("resolution"), Source::File(PathBuf::from("local.toml")));
+        assert_eq!(config.source("fullscreen"), Source::Default);
+    }
+
+    #[test]
+    fn errors_name_the_file_and_key() {
+        let mut config = Config::default();
+        let err = config.apply_toml("vsync = \"maybe\"\n", Path::new("local.toml")).unwrap_err();
+        assert_eq!(err.source, Source::File(PathBuf::from("local.toml")));
+        assert_eq!(err.key, Some("vsync".to_string()));
+        assert_eq!(err.to_string(), "local.toml: vsync: \"maybe\" is not true or false");
+
+        let err = config.apply_toml("vsync = \n", Path::new("local.toml")).unwrap_err();
+        assert_eq!(err.key, None);
+        assert!(err.to_string().starts_with("local.toml: "), "{}", err);
+
+        let vars = vec![(OsString::from("DX12_VSYNC"), OsString::from("maybe"))];
+        let err = config.apply_env(vars).unwrap_err();
+        assert_eq!(err.to_string(), "$DX12_VSYNC: \"maybe\" is not true or false");
+    }
+
+    #[test]
+    fn parses_resolutions() {
+        assert_eq!("800x600".parse::<Resolution>(), Ok(Resolution { width: 800, height: 600 }));
+        assert_eq!(" 1920 X 1080 ".parse::<Resolution>(),
+                   Ok(Resolution { width: 1920, height: 1080 }));
+        let largest = format!("{}x{}", MAX_DIMENSION, MAX_DIMENSION);
+        assert_eq!(largest.parse::<Resolution>(),
+                   Ok(Resolution { width: MAX_DIMENSION, height: MAX_DIMENSION }));
+    }
+
+    #[test]
+    fn rejects_bad_resolutions() {
+        let parse = |string: &str| string.parse::<Resolution>().unwrap_err();
+        assert_eq!(parse("0x0"), InvalidResolution::Zero);
+        assert_eq!(parse("800x0"), InvalidResolution::Zero);
+        assert_eq!(parse(&format!("{}x600", MAX_DIMENSION + 1)), InvalidResolution::TooLarge);
+        assert_eq!(parse("99999999999x600"), InvalidResolution::TooLarge);
+        assert_eq!(parse("800x"), InvalidResolution::NotANumber);
+        assert_eq!(parse("800x-600"), InvalidResolution::NotANumber);
+        assert_eq!(parse("800"), InvalidResolution::Syntax);
+    }
+
+    #[test]
+    fn later_profiles_win() {
+        let mut config = Config::default();
+        config.apply_profile("debug").unwrap();
+        config.apply_profile("perf").unwrap();
+        assert!(!config.enable_debug);
+        assert!(!config.trace_api);
+        assert_eq!(config.source("trace_api"), Source::Profile("perf".into()));
+        // perf doesn't say anything about debug_break, so debug's stays.
+        assert_eq!(config.source("debug_break"), Source::Profile("debug".into()));
+
+        let mut config = Config::default();
+        config.apply_profile("perf").unwrap();
+        config.apply_profile("debug").unwrap();
+        assert!(config.enable_debug);
+        assert!(config.trace_api);
+    }
+
+    #[test]
+    fn file_profiles_replace_built_ins() {
+        let mut config = Config::default();
+        config.apply_toml("[profiles.ci]\nvsync = false\n", Path::new("dx12.toml")).unwrap();
+        assert_eq!(config.profiles.iter().filter(|profile| profile.name == "ci").count(), 1);
+
+        config.apply_profile("ci").unwrap();
+        assert!(!config.vsync);
+        assert_eq!(config.source("vsync"), Source::Profile("ci".into()));
+        // None of the built-in ci's keys.
+        assert!(!config.force_warp);
+        assert_eq!(config.source("force_warp"), Source::Default);
+    }
+
+    #[test]
+    fn unknown_profiles_are_errors() {
+        let err = Config::default().apply_profile("fast").unwrap_err();
+        assert_eq!(err.source, Source::CommandLine);
+        assert_eq!(err.to_string(), "command line: profile: there's no profile named \"fast\"");
+    }
 }
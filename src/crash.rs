@@ -0,0 +1,238 @@
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Write},
+    panic,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use backtrace::Backtrace;
+
+use config;
+use error::WindowsError;
+
+/// Everything we'd like a tester to attach to a bug report.
+///
+/// Pieces get filled in as we learn them, so a report written early in
+/// startup will just be missing the later ones.
+#[derive(Default)]
+struct RunContext {
+    enabled:      bool,
+    config:       Option<String>,
+    adapter:      Option<String>,
+    last_failure: Option<String>,
+    dred:         Option<String>,
+}
+
+thread_local! {
+    static RUN_CONTEXT: RefCell<RunContext> = RefCell::new(RunContext::default());
+}
+
+/// Turn crash reports on, and record the config they should include.
+pub fn init(config: &config::Config) {
+    let mut text = vec![];
+    let _ = config.write_toml(&mut text);
+    RUN_CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        context.enabled = config.crash_report;
+        context.config  = Some(String::from_utf8_lossy(&text).into_owned());
+    });
+}
+
+/// Record the description of the adapter we're rendering with.
+pub fn set_adapter(description: String) {
+    RUN_CONTEXT.with(|context| context.borrow_mut().adapter = Some(description));
+}
+
+/// Record a formatted DRED report, after we've lost the device.
+pub fn set_dred(report: String) {
+    RUN_CONTEXT.with(|context| context.borrow_mut().dred = Some(report));
+}
+
+/// Record a failing `hr!` or `win32!` call. Used by the macros.
+pub fn set_last_failure(err: &WindowsError) {
+    let failure = match err.call_site() {
+        Some(site) => format!("{}:{}: {}\n{}",
+                              site.file,
+                              site.line,
+                              err.kind(),
+                              site.expr),
+        None => format!("{}", err.kind()),
+    };
+    // If we're somehow already borrowed, losing this is better than panicking.
+    let _ = RUN_CONTEXT.try_with(|context| {
+        if let Ok(mut context) = context.try_borrow_mut() {
+            context.last_failure = Some(failure);
+        }
+    });
+}
+
+/// Write a crash report for every panic, after the usual panic message.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None          => "Box<Any>".to_string(),
+            },
+        };
+        let reason = match info.location() {
+            Some(location) => format!("panicked at '{}', {}:{}",
+                                      message,
+                                      location.file(),
+                                      location.line()),
+            None => format!("panicked at '{}'", message),
+        };
+        report(&reason);
+    }));
+}
+
+/// Write a crash report for an error that's ending the process.
+pub fn report_error(err: &WindowsError) {
+    report(&err.to_string());
+}
+
+fn report(reason: &str) {
+    let enabled = RUN_CONTEXT.try_with(|context| {
+        context.try_borrow().map(|context| context.enabled).unwrap_or(false)
+    }).unwrap_or(false);
+    if !enabled {
+        return;
+    }
+
+    match write_report(reason) {
+        Ok(path) => eprintln!("Wrote crash report to {}", path.display()),
+        Err(err) => eprintln!("Couldn't write crash report: {}", err),
+    }
+}
+
+fn write_report(reason: &str) -> io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let path = PathBuf::from(format!("dx12-crash-{}.txt", timestamp));
+    let mut file = File::create(&path)?;
+    let backtrace = format!("{:?}", Backtrace::new());
+
+    RUN_CONTEXT.with(|context| {
+        // If we're somehow already borrowed, write what we can without it.
+        let borrowed = context.try_borrow().ok();
+        let context = borrowed.as_ref().map(|context| &**context);
+        write_report_to(&mut file, timestamp, reason, context, &backtrace)
+    })?;
+
+    Ok(path)
+}
+
+/// The body of a crash report. Without a `context`, only the reason and
+/// backtrace are written.
+fn write_report_to<W: Write>(w:         &mut W,
+                             timestamp: u64,
+                             reason:    &str,
+                             context:   Option<&RunContext>,
+                             backtrace: &str)
+    -> io::Result<()>
+{
+    writeln!(w, "dx12 crash report")?;
+    writeln!(w, "=================")?;
+    writeln!(w)?;
+    writeln!(w, "Time:    {} (seconds since the Unix epoch)", timestamp)?;
+    writeln!(w, "Version: {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(w, "Reason:  {}", reason)?;
+
+    if let Some(context) = context {
+        write_section(w, "Last failing call", context.last_failure.as_ref().map(String::as_str))?;
+        write_section(w, "Config",            context.config.as_ref().map(String::as_str))?;
+        write_section(w, "Adapter",           context.adapter.as_ref().map(String::as_str))?;
+        if let Some(ref dred) = context.dred {
+            write_section(w, "Device Removed Extended Data", Some(dred))?;
+        }
+    }
+
+    write_section(w, "Backtrace", Some(backtrace))
+}
+
+fn write_section<W: Write>(w: &mut W, title: &str, body: Option<&str>) -> io::Result<()> {
+    writeln!(w)?;
+    writeln!(w, "{}:", title)?;
+    match body {
+        Some(body) => {
+            for line in body.lines() {
+                writeln!(w, "    {}", line)?;
+            }
+        },
+        None => writeln!(w, "    <unknown>")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(context: Option<&RunContext>) -> String {
+        let mut text = vec![];
+        let reason = "panicked at 'oops', src/main.rs:1";
+        write_report_to(&mut text, 1234, reason, context, "frame 0\nframe 1").unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    fn header() -> String {
+        format!("dx12 crash report\n\
+                 =================\n\
+                 \n\
+                 Time:    1234 (seconds since the Unix epoch)\n\
+                 Version: {}\n\
+                 Reason:  panicked at 'oops', src/main.rs:1\n",
+                env!("CARGO_PKG_VERSION"))
+    }
+
+    #[test]
+    fn marks_what_we_never_learned_as_unknown() {
+        let context = RunContext {
+            config: Some("width = 800\nheight = 600\n".to_string()),
+            ..RunContext::default()
+        };
+        assert_eq!(report(Some(&context)), header() + "\n\
+                   Last failing call:\n    <unknown>\n\
+                   \n\
+                   Config:\n    width = 800\n    height = 600\n\
+                   \n\
+                   Adapter:\n    <unknown>\n\
+                   \n\
+                   Backtrace:\n    frame 0\n    frame 1\n");
+    }
+
+    #[test]
+    fn includes_dred_only_once_we_have_it() {
+        let context = RunContext {
+            enabled:      true,
+            config:       Some("width = 800".to_string()),
+            adapter:      Some("WARP".to_string()),
+            last_failure: Some("src/renderer.rs:1: DXGI_ERROR_DEVICE_REMOVED\nPresent()".to_string()),
+            dred:         Some("Breadcrumbs:\n  Draw".to_string()),
+        };
+        assert_eq!(report(Some(&context)), header() + "\n\
+                   Last failing call:\n    src/renderer.rs:1: DXGI_ERROR_DEVICE_REMOVED\n    Present()\n\
+                   \n\
+                   Config:\n    width = 800\n\
+                   \n\
+                   Adapter:\n    WARP\n\
+                   \n\
+                   Device Removed Extended Data:\n    Breadcrumbs:\n      Draw\n\
+                   \n\
+                   Backtrace:\n    frame 0\n    frame 1\n");
+    }
+
+    #[test]
+    fn writes_the_backtrace_without_a_context() {
+        assert_eq!(report(None), header() + "\nBacktrace:\n    frame 0\n    frame 1\n");
+    }
+}
@@ -0,0 +1,594 @@
+
+//! Device Removed Extended Data (DRED)
+//!
+//! With DRED on, the runtime keeps "breadcrumbs" of every op it records into a
+//! command list, and how far the GPU got through them. When we lose the device,
+//! that tells us which command list and which op were in flight, and for page
+//! faults, which allocations lived at (or were just freed from) the address.
+//!
+//! `read()` copies what the runtime has into a `DredReport`, and
+//! `write_report()` prints that.
+
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use error::HResult;
+
+/// Everything DRED could tell us about a removed device.
+#[derive(Debug, Clone, Default)]
+pub struct DredReport {
+    /// What `GetDeviceRemovedReason()` said, if we asked.
+    pub reason:      Option<HResult>,
+    /// One node per command list the runtime was tracking, oldest first.
+    pub breadcrumbs: Vec<BreadcrumbNode>,
+    pub page_fault:  Option<PageFault>,
+}
+
+/// The ops recorded into one command list, and how many the GPU finished.
+#[derive(Debug, Clone, Default)]
+pub struct BreadcrumbNode {
+    pub command_list:  Option<String>,
+    pub command_queue: Option<String>,
+    pub history:       Vec<BreadcrumbOp>,
+    /// How many ops at the start of `history` completed.
+    pub completed:     u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NodeStatus {
+    NotStarted,
+    InFlight,
+    Completed,
+}
+
+impl DredReport {
+    /// The status of each of `breadcrumbs`.
+    ///
+    /// A list with no completed ops may not have started, or op 0 may be what
+    /// hung the GPU. We can't tell which, so the first unfinished list on
+    /// each queue counts as in flight, and the ones queued behind it don't.
+    pub fn statuses(&self) -> Vec<NodeStatus> {
+        let mut busy_queues: Vec<&Option<String>> = vec![];
+        self.breadcrumbs
+            .iter()
+            .map(|node| {
+                let status = node.status();
+                if status == NodeStatus::Completed {
+                    return status;
+                }
+                if busy_queues.contains(&&node.command_queue) {
+                    return status;
+                }
+                busy_queues.push(&node.command_queue);
+                NodeStatus::InFlight
+            })
+            .collect()
+    }
+}
+
+impl BreadcrumbNode {
+    /// What this node says about itself, without the rest of the report.
+    /// See `DredReport::statuses()`.
+    pub fn status(&self) -> NodeStatus {
+        if self.completed as usize >= self.history.len() {
+            NodeStatus::Completed
+        } else if self.completed == 0 {
+            NodeStatus::NotStarted
+        } else {
+            NodeStatus::InFlight
+        }
+    }
+
+    /// The first op the GPU didn't finish, and its index in `history`.
+    pub fn in_flight(&self) -> Option<(usize, BreadcrumbOp)> {
+        let index = self.completed as usize;
+        self.history.get(index).map(|&op| (index, op))
+    }
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NodeStatus::NotStarted => write!(f, "not started"),
+            NodeStatus::InFlight   => write!(f, "in flight"),
+            NodeStatus::Completed  => write!(f, "completed"),
+        }
+    }
+}
+
+/// A `D3D12_AUTO_BREADCRUMB_OP`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BreadcrumbOp(pub u32);
+
+impl BreadcrumbOp {
+    pub fn name(self) -> Option<&'static str> {
+        let name = match self.0 {
+            0  => "SetMarker",
+            1  => "BeginEvent",
+            2  => "EndEvent",
+            3  => "DrawInstanced",
+            4  => "DrawIndexedInstanced",
+            5  => "ExecuteIndirect",
+            6  => "Dispatch",
+            7  => "CopyBufferRegion",
+            8  => "CopyTextureRegion",
+            9  => "CopyResource",
+            10 => "CopyTiles",
+            11 => "ResolveSubresource",
+            12 => "ClearRenderTargetView",
+            13 => "ClearUnorderedAccessView",
+            14 => "ClearDepthStencilView",
+            15 => "ResourceBarrier",
+            16 => "ExecuteBundle",
+            17 => "Present",
+            18 => "ResolveQueryData",
+            19 => "BeginSubmission",
+            20 => "EndSubmission",
+            21 => "DecodeFrame",
+            22 => "ProcessFrames",
+            23 => "AtomicCopyBufferUint",
+            24 => "AtomicCopyBufferUint64",
+            25 => "ResolveSubresourceRegion",
+            26 => "WriteBufferImmediate",
+            27 => "DecodeFrame1",
+            28 => "SetProtectedResourceSession",
+            29 => "DecodeFrame2",
+            30 => "ProcessFrames1",
+            31 => "BuildRaytracingAccelerationStructure",
+            32 => "EmitRaytracingAccelerationStructurePostbuildInfo",
+            33 => "CopyRaytracingAccelerationStructure",
+            34 => "DispatchRays",
+            35 => "InitializeMetaCommand",
+            36 => "ExecuteMetaCommand",
+            37 => "EstimateMotion",
+            38 => "ResolveMotionVectorHeap",
+            39 => "SetPipelineState1",
+            40 => "InitializeExtensionCommand",
+            41 => "ExecuteExtensionCommand",
+            42 => "DispatchMesh",
+            _  => return None,
+        };
+        Some(name)
+    }
+}
+
+impl fmt::Display for BreadcrumbOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None       => write!(f, "Unknown op {}", self.0),
+        }
+    }
+}
+
+/// What DRED saw at the GPU virtual address that faulted.
+#[derive(Debug, Clone, Default)]
+pub struct PageFault {
+    pub address:        u64,
+    pub existing:       Vec<Allocation>,
+    pub recently_freed: Vec<Allocation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub name: Option<String>,
+    pub kind: AllocationType,
+}
+
+/// A `D3D12_DRED_ALLOCATION_TYPE`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AllocationType(pub u32);
+
+impl AllocationType {
+    pub fn name(self) -> Option<&'static str> {
+        let name = match self.0 {
+            19 => "CommandQueue",
+            20 => "CommandAllocator",
+            21 => "PipelineState",
+            22 => "CommandList",
+            23 => "Fence",
+            24 => "DescriptorHeap",
+            25 => "Heap",
+            27 => "QueryHeap",
+            28 => "CommandSignature",
+            29 => "PipelineLibrary",
+            30 => "VideoDecoder",
+            32 => "VideoProcessor",
+            34 => "Resource",
+            35 => "Pass",
+            36 => "CryptoSession",
+            37 => "CryptoSessionPolicy",
+            38 => "ProtectedResourceSession",
+            39 => "VideoDecoderHeap",
+            40 => "CommandPool",
+            41 => "CommandRecorder",
+            42 => "StateObject",
+            43 => "MetaCommand",
+            44 => "SchedulingGroup",
+            45 => "VideoMotionEstimator",
+            46 => "VideoMotionVectorHeap",
+            47 => "VideoExtensionCommand",
+            _  => return None,
+        };
+        Some(name)
+    }
+}
+
+impl fmt::Display for AllocationType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None       => write!(f, "Unknown allocation type {}", self.0),
+        }
+    }
+}
+
+/// Write `report` for a human.
+///
+/// ```text
+/// Device removed: DXGI_ERROR_DEVICE_HUNG (facility DXGI 0x87a, code 0x6)
+///
+/// Command lists (2):
+///     [completed]   "Frame 12" on "Direct Queue": 3 of 3 ops
+///     [in flight]   "Frame 13" on "Direct Queue": 1 of 3 ops
+///               0  ResourceBarrier
+///           ->  1  DrawInstanced
+///               2  ResourceBarrier
+///
+/// Page fault at GPU address 0x0000000012340000:
+///     Existing allocations:
+///         Resource "Vertex Buffer"
+///     Recently freed allocations:
+///         <none>
+/// ```
+pub fn write_report<W: Write>(w: &mut W, report: &DredReport) -> io::Result<()> {
+    match report.reason {
+        Some(reason) => writeln!(w, "Device removed: {}", reason)?,
+        None         => writeln!(w, "Device removed")?,
+    }
+
+    writeln!(w)?;
+    if report.breadcrumbs.is_empty() {
+        writeln!(w, "No breadcrumbs. Was DRED enabled before creating the device?")?;
+    } else {
+        writeln!(w, "Command lists ({}):", report.breadcrumbs.len())?;
+        for (node, status) in report.breadcrumbs.iter().zip(report.statuses()) {
+            write_node(w, node, status)?;
+        }
+    }
+
+    if let Some(ref fault) = report.page_fault {
+        writeln!(w)?;
+        writeln!(w, "Page fault at GPU address 0x{:016x}:", fault.address)?;
+        writeln!(w, "    Existing allocations:")?;
+        write_allocations(w, &fault.existing)?;
+        writeln!(w, "    Recently freed allocations:")?;
+        write_allocations(w, &fault.recently_freed)?;
+    }
+
+    Ok(())
+}
+
+fn write_node<W: Write>(w: &mut W, node: &BreadcrumbNode, status: NodeStatus) -> io::Result<()> {
+    writeln!(w, "    {:<13} {} on {}: {} of {} ops",
+             format!("[{}]", status),
+             quoted_or_unnamed(&node.command_list),
+             quoted_or_unnamed(&node.command_queue),
+             node.completed.min(node.history.len() as u32),
+             node.history.len())?;
+
+    // Finished and untouched lists can't be what hung the GPU, so only list
+    // the ops of the one(s) that were running.
+    if status != NodeStatus::InFlight {
+        return Ok(());
+    }
+    let current = node.in_flight().map(|(index, _op)| index);
+    for (index, op) in node.history.iter().enumerate() {
+        let marker = if Some(index) == current { "->" } else { "  " };
+        writeln!(w, "          {} {:>2}  {}", marker, index, op)?;
+    }
+    Ok(())
+}
+
+fn write_allocations<W: Write>(w: &mut W, allocations: &[Allocation]) -> io::Result<()> {
+    if allocations.is_empty() {
+        return writeln!(w, "        <none>");
+    }
+    for allocation in allocations {
+        writeln!(w, "        {} {}",
+                 allocation.kind,
+                 quoted_or_unnamed(&allocation.name))?;
+    }
+    Ok(())
+}
+
+fn quoted_or_unnamed(name: &Option<String>) -> String {
+    match *name {
+        Some(ref name) => format!("\"{}\"", name),
+        None           => "<unnamed>".to_string(),
+    }
+}
+
+#[cfg(windows)]
+pub use self::windows::{enable, read};
+
+#[cfg(windows)]
+#[allow(non_camel_case_types, non_snake_case)]
+mod windows {
+    use std::{
+        ffi::CStr,
+        ptr,
+        slice,
+    };
+
+    use wio::com::ComPtr;
+
+    use winapi::{
+        Interface,
+        ctypes::{c_char, wchar_t},
+        um::d3d12::*,
+        um::unknwnbase::{IUnknown, IUnknownVtbl},
+        um::winnt::HRESULT,
+    };
+
+    use error::*;
+    use super::*;
+
+    // Our winapi doesn't have the DRED interfaces yet, so here's enough of
+    // d3d12.h to use them.
+
+    pub type D3D12_DRED_ENABLEMENT = u32;
+    pub const D3D12_DRED_ENABLEMENT_FORCED_ON: D3D12_DRED_ENABLEMENT = 2;
+
+    #[repr(C)]
+    pub struct D3D12_AUTO_BREADCRUMB_NODE {
+        pCommandListDebugNameA:  *const c_char,
+        pCommandListDebugNameW:  *const wchar_t,
+        pCommandQueueDebugNameA: *const c_char,
+        pCommandQueueDebugNameW: *const wchar_t,
+        pCommandList:            *mut ID3D12GraphicsCommandList,
+        pCommandQueue:           *mut ID3D12CommandQueue,
+        BreadcrumbCount:         u32,
+        pLastBreadcrumbValue:    *const u32,
+        pCommandHistory:         *const u32,
+        pNext:                   *const D3D12_AUTO_BREADCRUMB_NODE,
+    }
+
+    #[repr(C)]
+    pub struct D3D12_DRED_AUTO_BREADCRUMBS_OUTPUT {
+        pHeadAutoBreadcrumbNode: *const D3D12_AUTO_BREADCRUMB_NODE,
+    }
+
+    #[repr(C)]
+    pub struct D3D12_DRED_ALLOCATION_NODE {
+        ObjectNameA:    *const c_char,
+        ObjectNameW:    *const wchar_t,
+        AllocationType: u32,
+        pNext:          *const D3D12_DRED_ALLOCATION_NODE,
+    }
+
+    #[repr(C)]
+    pub struct D3D12_DRED_PAGE_FAULT_OUTPUT {
+        PageFaultVA:                    D3D12_GPU_VIRTUAL_ADDRESS,
+        pHeadExistingAllocationNode:    *const D3D12_DRED_ALLOCATION_NODE,
+        pHeadRecentFreedAllocationNode: *const D3D12_DRED_ALLOCATION_NODE,
+    }
+
+    RIDL!{#[uuid(0x82bc481c, 0x6b9b, 0x4030, 0xae, 0xdb, 0x7e, 0xe3, 0xd1, 0xdf, 0x1e, 0x63)]
+    interface ID3D12DeviceRemovedExtendedDataSettings(ID3D12DeviceRemovedExtendedDataSettingsVtbl):
+        IUnknown(IUnknownVtbl) {
+        fn SetAutoBreadcrumbsEnablement(
+            Enablement: D3D12_DRED_ENABLEMENT,
+        ) -> (),
+        fn SetPageFaultEnablement(
+            Enablement: D3D12_DRED_ENABLEMENT,
+        ) -> (),
+        fn SetWatsonDumpEnablement(
+            Enablement: D3D12_DRED_ENABLEMENT,
+        ) -> (),
+    }}
+
+    RIDL!{#[uuid(0x98931d33, 0x5ae8, 0x4791, 0xaa, 0x3c, 0x1a, 0x73, 0xa2, 0x93, 0x4e, 0x71)]
+    interface ID3D12DeviceRemovedExtendedData(ID3D12DeviceRemovedExtendedDataVtbl):
+        IUnknown(IUnknownVtbl) {
+        fn GetAutoBreadcrumbsOutput(
+            pOutput: *mut D3D12_DRED_AUTO_BREADCRUMBS_OUTPUT,
+        ) -> HRESULT,
+        fn GetPageFaultAllocationOutput(
+            pOutput: *mut D3D12_DRED_PAGE_FAULT_OUTPUT,
+        ) -> HRESULT,
+    }}
+
+    /// Turn on auto-breadcrumbs and page fault reporting.
+    /// This only affects devices created afterwards.
+    pub fn enable() -> WindowsResult<()> {
+        let settings: ComPtr<ID3D12DeviceRemovedExtendedDataSettings> = unsafe {
+            let mut ptr: *mut _ = ptr::null_mut();
+            hr!(D3D12GetDebugInterface(&ID3D12DeviceRemovedExtendedDataSettings::uuidof(),
+                                       &mut ptr as *mut _ as *mut _))?;
+            ComPtr::from_raw(ptr)
+        };
+        unsafe {
+            settings.SetAutoBreadcrumbsEnablement(D3D12_DRED_ENABLEMENT_FORCED_ON);
+            settings.SetPageFaultEnablement(D3D12_DRED_ENABLEMENT_FORCED_ON);
+        }
+        Ok(())
+    }
+
+    /// Collect what DRED knows about why `device` was removed.
+    pub fn read(device: &ComPtr<ID3D12Device>) -> WindowsResult<DredReport> {
+        let dred: ComPtr<ID3D12DeviceRemovedExtendedData> = device.cast()?;
+        let mut report = DredReport::default();
+
+        let reason = unsafe { device.GetDeviceRemovedReason() };
+        report.reason = Some(HResult(reason));
+
+        unsafe {
+            let mut output: D3D12_DRED_AUTO_BREADCRUMBS_OUTPUT = ::std::mem::zeroed();
+            hr!(dred.GetAutoBreadcrumbsOutput(&mut output))?;
+
+            let mut node = output.pHeadAutoBreadcrumbNode;
+            while let Some(n) = node.as_ref() {
+                let history = if n.pCommandHistory.is_null() {
+                    &[][..]
+                } else {
+                    slice::from_raw_parts(n.pCommandHistory, n.BreadcrumbCount as usize)
+                };
+                report.breadcrumbs.push(BreadcrumbNode {
+                    command_list:  debug_name(n.pCommandListDebugNameW,
+                                              n.pCommandListDebugNameA),
+                    command_queue: debug_name(n.pCommandQueueDebugNameW,
+                                              n.pCommandQueueDebugNameA),
+                    history:       history.iter().map(|&op| BreadcrumbOp(op)).collect(),
+                    completed:     n.pLastBreadcrumbValue.as_ref().cloned().unwrap_or(0),
+                });
+                node = n.pNext;
+            }
+        }
+
+        unsafe {
+            let mut output: D3D12_DRED_PAGE_FAULT_OUTPUT = ::std::mem::zeroed();
+            hr!(dred.GetPageFaultAllocationOutput(&mut output))?;
+
+            // A zero address means there wasn't a page fault.
+            if output.PageFaultVA != 0 {
+                report.page_fault = Some(PageFault {
+                    address:        output.PageFaultVA,
+                    existing:       allocations(output.pHeadExistingAllocationNode),
+                    recently_freed: allocations(output.pHeadRecentFreedAllocationNode),
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    unsafe fn allocations(mut node: *const D3D12_DRED_ALLOCATION_NODE) -> Vec<Allocation> {
+        let mut allocations = vec![];
+        while let Some(n) = node.as_ref() {
+            allocations.push(Allocation {
+                name: debug_name(n.ObjectNameW, n.ObjectNameA),
+                kind: AllocationType(n.AllocationType),
+            });
+            node = n.pNext;
+        }
+        allocations
+    }
+
+    /// Debug names come as either UTF-16 or ANSI, whichever was set.
+    unsafe fn debug_name(wide: *const wchar_t, narrow: *const c_char) -> Option<String> {
+        if !wide.is_null() {
+            let len = (0..).take_while(|&i| *wide.offset(i) != 0).count();
+            let wide = slice::from_raw_parts(wide, len);
+            return Some(String::from_utf16_lossy(wide));
+        }
+        if !narrow.is_null() {
+            return Some(CStr::from_ptr(narrow).to_string_lossy().into_owned());
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(list: &str, queue: &str, history: &[u32], completed: u32) -> BreadcrumbNode {
+        BreadcrumbNode {
+            command_list:  Some(list.to_string()),
+            command_queue: Some(queue.to_string()),
+            history:       history.iter().map(|&op| BreadcrumbOp(op)).collect(),
+            completed,
+        }
+    }
+
+    fn report_text(report: &DredReport) -> String {
+        let mut text = vec![];
+        write_report(&mut text, report).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    // ResourceBarrier, DrawInstanced, ResourceBarrier
+    const FRAME: &[u32] = &[15, 3, 15];
+
+    #[test]
+    fn points_at_the_op_in_flight() {
+        let report = DredReport {
+            reason:      Some(HResult(0x887A0006u32 as i32)),
+            breadcrumbs: vec![node("Frame 12", "Direct", FRAME, 3),
+                              node("Frame 13", "Direct", FRAME, 1)],
+            page_fault:  None,
+        };
+        assert_eq!(report.statuses(), vec![NodeStatus::Completed, NodeStatus::InFlight]);
+        assert_eq!(report_text(&report), "\
+Device removed: DXGI_ERROR_DEVICE_HUNG (facility DXGI 0x87a, code 0x6)
+
+Command lists (2):
+    [completed]   \"Frame 12\" on \"Direct\": 3 of 3 ops
+    [in flight]   \"Frame 13\" on \"Direct\": 1 of 3 ops
+              0  ResourceBarrier
+          ->  1  DrawInstanced
+              2  ResourceBarrier
+");
+    }
+
+    #[test]
+    fn op_zero_can_be_in_flight() {
+        let report = DredReport {
+            breadcrumbs: vec![node("Frame 1", "Direct", FRAME, 0),
+                              node("Frame 2", "Direct", FRAME, 0)],
+            ..DredReport::default()
+        };
+        // Frame 2 is queued behind Frame 1, which could have hung on op 0.
+        assert_eq!(report.statuses(), vec![NodeStatus::InFlight, NodeStatus::NotStarted]);
+        let text = report_text(&report);
+        assert!(text.contains("[in flight]   \"Frame 1\" on \"Direct\": 0 of 3 ops\n          \
+                               ->  0  ResourceBarrier\n"), "{}", text);
+        assert!(text.contains("[not started] \"Frame 2\" on \"Direct\": 0 of 3 ops\n"), "{}", text);
+    }
+
+    #[test]
+    fn each_queue_has_its_own_list_in_flight() {
+        let report = DredReport {
+            breadcrumbs: vec![node("Draw", "Direct", FRAME, 2),
+                              node("Blur", "Compute", &[6, 6], 0),
+                              node("Next", "Direct", FRAME, 0),
+                              node("Done", "Copy", &[7], 1)],
+            ..DredReport::default()
+        };
+        assert_eq!(report.statuses(), vec![NodeStatus::InFlight,
+                                           NodeStatus::InFlight,
+                                           NodeStatus::NotStarted,
+                                           NodeStatus::Completed]);
+    }
+
+    #[test]
+    fn writes_page_faults_and_unknown_values() {
+        let report = DredReport {
+            reason:      None,
+            breadcrumbs: vec![],
+            page_fault:  Some(PageFault {
+                address:        0x12340000,
+                existing:       vec![Allocation {
+                    name: Some("Vertex Buffer".to_string()),
+                    kind: AllocationType(34),
+                }],
+                recently_freed: vec![Allocation { name: None, kind: AllocationType(1000) }],
+            }),
+        };
+        assert_eq!(report_text(&report), "\
+Device removed
+
+No breadcrumbs. Was DRED enabled before creating the device?
+
+Page fault at GPU address 0x0000000012340000:
+    Existing allocations:
+        Resource \"Vertex Buffer\"
+    Recently freed allocations:
+        Unknown allocation type 1000 <unnamed>
+");
+        assert_eq!(BreadcrumbOp(999).to_string(), "Unknown op 999");
+    }
+}
@@ -1,105 +1,1175 @@
-
 use std::{
+    borrow::Cow,
+    cell::RefCell,
+    env,
+    error,
     fmt,
-    ptr,
-    str,
+    io,
+    path::{Path, PathBuf},
 };
 
-use winapi::{
-    shared::winerror,
-    shared::winerror::HRESULT,
+use atty;
+use termcolor;
+
+use config::{
+    ColorMode,
+    ErrorFormat,
 };
 
-use termcolor;
+/// Same as `winapi::shared::winerror::HRESULT`, but also available off of
+/// Windows so that decoding and formatting work anywhere.
+pub type HRESULT = i32;
 
 pub type WindowsResult<T> = Result<T, WindowsError>;
 
-#[derive(Copy, Clone)]
-pub enum WindowsError {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
     NotImplemented,
-    Hresult(HRESULT),
+    NoAdapter,
+    Hresult(HResult),
+    Win32(Win32Error),
+    /// A Win32 call failed, but left `GetLastError()` at zero.
+    NoLastError,
 }
 
-impl fmt::Debug for WindowsError {
+impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use ::error::WindowsError as W;
+        match *self {
+            ErrorKind::NotImplemented => write!(f, "Not implemented"),
+            ErrorKind::NoAdapter      => write!(f, "No suitable adapter"),
+            ErrorKind::Hresult(hr)    => write!(f, "{}", hr),
+            ErrorKind::Win32(err)     => write!(f, "{}", err),
+            ErrorKind::NoLastError    => write!(f, "Failed without setting a last error"),
+        }
+    }
+}
+
+/// Broad buckets of failure, so scripts running us can tell them apart by
+/// exit code.
+///
+/// | Exit code | Category         |
+/// |-----------|------------------|
+/// | 0         | (success)        |
+/// | 1         | `Other`          |
+/// | 2         | `InvalidConfig`  |
+/// | 3         | `NoAdapter`      |
+/// | 4         | `DeviceCreation` |
+/// | 5         | `DeviceRemoved`  |
+/// | 6         | `NotImplemented` |
+/// | 101       | (panic)          |
+///
+/// These numbers are stable: add new categories at the end.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Category {
+    Other,
+    InvalidConfig,
+    NoAdapter,
+    DeviceCreation,
+    DeviceRemoved,
+    NotImplemented,
+}
+
+impl Category {
+    pub fn exit_code(self) -> i32 {
         match self {
-            W::NotImplemented => write!(f, "NotImplemented"),
-            W::Hresult(hr)    => write!(f, "HRESULT={}", win_error_msg(*hr)),
+            Category::Other          => 1,
+            Category::InvalidConfig  => 2,
+            Category::NoAdapter      => 3,
+            Category::DeviceCreation => 4,
+            Category::DeviceRemoved  => 5,
+            Category::NotImplemented => 6,
         }
     }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Other          => "other",
+            Category::InvalidConfig  => "invalid_config",
+            Category::NoAdapter      => "no_adapter",
+            Category::DeviceCreation => "device_creation",
+            Category::DeviceRemoved  => "device_removed",
+            Category::NotImplemented => "not_implemented",
+        }
+    }
+}
+
+/// Where an `hr!` or `win32!` failure came from.
+#[derive(Debug, Copy, Clone)]
+pub struct CallSite {
+    pub file: &'static str,
+    pub line: u32,
+    /// The expression passed to the macro, as written.
+    pub expr: &'static str,
+}
+
+/// An error, along with whatever we know about how we got there.
+///
+/// `hr!` and `win32!` fill in the call site. Callers further up can describe what they
+/// were doing with `.context()`, which reads outermost-first when displayed.
+#[derive(Clone)]
+pub struct WindowsError {
+    kind:      ErrorKind,
+    category:  Option<Category>,
+    call_site: Option<CallSite>,
+    context:   Vec<String>,
+}
+
+impl WindowsError {
+    pub fn new(kind: ErrorKind) -> WindowsError {
+        WindowsError {
+            kind,
+            category:  None,
+            call_site: None,
+            context:   vec![],
+        }
+    }
+
+    pub fn not_implemented() -> WindowsError {
+        WindowsError::new(ErrorKind::NotImplemented)
+    }
+
+    /// Used by `hr!`.
+    pub fn from_call_site(hresult: HRESULT, call_site: CallSite) -> WindowsError {
+        WindowsError {
+            call_site: Some(call_site),
+            ..WindowsError::new(ErrorKind::Hresult(HResult(hresult)))
+        }
+    }
+
+    /// Used by `win32!`.
+    ///
+    /// A zero `err` would read as `ERROR_SUCCESS` (and `S_OK`), so it gets its own kind.
+    pub fn from_win32_call_site(err: Win32Error, call_site: CallSite) -> WindowsError {
+        let kind = match err {
+            Win32Error(0) => ErrorKind::NoLastError,
+            err           => ErrorKind::Win32(err),
+        };
+        WindowsError {
+            call_site: Some(call_site),
+            ..WindowsError::new(kind)
+        }
+    }
+
+    /// Describe what we were doing when this error happened.
+    pub fn context<S: Into<String>>(mut self, context: S) -> WindowsError {
+        self.context.push(context.into());
+        self
+    }
+
+    /// Say which bucket this error belongs in, when its kind alone can't.
+    pub fn with_category(mut self, category: Category) -> WindowsError {
+        self.category = Some(category);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The category set with `with_category()`, or one guessed from the kind.
+    pub fn category(&self) -> Category {
+        if let Some(category) = self.category {
+            return category;
+        }
+        match self.kind {
+            ErrorKind::NotImplemented => Category::NotImplemented,
+            ErrorKind::NoAdapter      => Category::NoAdapter,
+            ErrorKind::Hresult(hr) if hr.is_device_lost() => Category::DeviceRemoved,
+            _                         => Category::Other,
+        }
+    }
+
+    /// The `HRESULT` for this error. Win32 errors are converted with
+    /// `HRESULT_FROM_WIN32`.
+    pub fn hresult(&self) -> Option<HResult> {
+        match self.kind {
+            ErrorKind::Hresult(hr) => Some(hr),
+            ErrorKind::Win32(err)  => Some(err.to_hresult()),
+            _                      => None,
+        }
+    }
+
+    pub fn call_site(&self) -> Option<&CallSite> {
+        self.call_site.as_ref()
+    }
+
+    /// Context annotations, innermost first.
+    pub fn context_chain(&self) -> &[String] {
+        &self.context
+    }
+}
+
+impl fmt::Debug for WindowsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WindowsError")
+            .field("kind",      &self.kind)
+            .field("category",  &self.category)
+            .field("call_site", &self.call_site)
+            .field("context",   &self.context)
+            .finish()
+    }
+}
+
+impl fmt::Display for WindowsError {
+    /// e.g. `creating swapchain: E_INVALIDARG (facility WIN32 0x7, code 0x57) at src/renderer.rs:420`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for context in self.context.iter().rev() {
+            write!(f, "{}: ", context)?;
+        }
+        write!(f, "{}", self.kind)?;
+        if let Some(ref site) = self.call_site {
+            write!(f, " at {}:{}", site.file, site.line)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for WindowsError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ErrorKind::NotImplemented => "not implemented",
+            ErrorKind::NoAdapter      => "no suitable adapter",
+            ErrorKind::Hresult(hr)    => hr.name().unwrap_or("HRESULT failure"),
+            ErrorKind::Win32(err)     => err.name().unwrap_or("Win32 failure"),
+            ErrorKind::NoLastError    => "failed without setting a last error",
+        }
+    }
+}
+
+impl From<Win32Error> for WindowsError {
+    fn from(err: Win32Error) -> WindowsError {
+        WindowsError::new(ErrorKind::Win32(err))
+    }
 }
 
 impl From<HRESULT> for WindowsError {
     fn from(hresult: HRESULT) -> WindowsError {
-        WindowsError::Hresult(hresult)
+        WindowsError::new(ErrorKind::Hresult(HResult(hresult)))
+    }
+}
+
+/// Adds `.context()` to anything that can fail with a `WindowsError`.
+pub trait ResultExt<T> {
+    fn context<S: Into<String>>(self, context: S) -> WindowsResult<T>;
+    fn with_category(self, category: Category) -> WindowsResult<T>;
+}
+
+impl<T, E: Into<WindowsError>> ResultExt<T> for Result<T, E> {
+    fn context<S: Into<String>>(self, context: S) -> WindowsResult<T> {
+        self.map_err(|err| err.into().context(context))
+    }
+
+    fn with_category(self, category: Category) -> WindowsResult<T> {
+        self.map_err(|err| err.into().with_category(category))
+    }
+}
+
+/// How `print_error` renders errors.
+pub struct ErrorStyle {
+    pub format:  ErrorFormat,
+    pub color:   termcolor::ColorChoice,
+    pub catalog: ColorSpecCatalog,
+}
+
+impl ErrorStyle {
+    pub fn new(mode: ColorMode, catalog: ColorSpecCatalog) -> ErrorStyle {
+        ErrorStyle {
+            format: ErrorFormat::Human,
+            color:  color_choice(mode),
+            catalog,
+        }
+    }
+
+    pub fn with_format(mut self, format: ErrorFormat) -> ErrorStyle {
+        self.format = format;
+        self
+    }
+}
+
+thread_local! {
+    static ERROR_STYLE: RefCell<ErrorStyle> =
+        RefCell::new(ErrorStyle::new(ColorMode::Auto, get_color_spec_catalog()));
+}
+
+/// Set how `print_error` renders errors from now on.
+pub fn set_error_style(style: ErrorStyle) {
+    ERROR_STYLE.with(|current| *current.borrow_mut() = style);
+}
+
+/// Resolve `--color` against the environment.
+pub fn color_choice(mode: ColorMode) -> termcolor::ColorChoice {
+    use termcolor::ColorChoice;
+
+    match mode {
+        ColorMode::Always => ColorChoice::Always,
+        ColorMode::Never  => ColorChoice::Never,
+        ColorMode::Auto   => {
+            // https://no-color.org/
+            let no_color = env::var_os("NO_COLOR")
+                .map(|value| !value.is_empty())
+                .unwrap_or(false);
+            if no_color || !atty::is(atty::Stream::Stderr) {
+                ColorChoice::Never
+            } else {
+                // This still checks TERM for us.
+                ColorChoice::Auto
+            }
+        },
+    }
+}
+
+/// Print `err` to stderr with all of the context we have.
+pub fn print_error(err: &WindowsError) {
+    use termcolor::StandardStream;
+
+    ERROR_STYLE.with(|style| {
+        let style = style.borrow();
+        // If we can't write to stderr, there's nowhere left to complain.
+        let _ = match style.format {
+            ErrorFormat::Human => {
+                let mut stderr = StandardStream::stderr(style.color);
+                write_error(&mut stderr, &style.catalog, err)
+            },
+            ErrorFormat::Json => {
+                let stderr = io::stderr();
+                let mut stderr = stderr.lock();
+                write_error_json(&mut stderr, err)
+            },
+        };
+    });
+}
+
+/// Write `err` as a single line of JSON.
+///
+/// ```text
+/// {"kind":"hresult","category":"device_removed","win32_code":null,
+///  "file":"src\\renderer.rs","line":420,"expression":"...",
+///  "hresult":"0x887a0005","severity":"failure","facility":"DXGI","facility_code":2170,
+///  "code":5,"name":"DXGI_ERROR_DEVICE_REMOVED","description":"...",
+///  "message":"...","context":["creating the swapchain","creating the renderer"]}
+/// ```
+///
+/// Fields we don't know are `null`. `context` is innermost-first.
+pub fn write_error_json<W: io::Write>(w: &mut W, err: &WindowsError) -> io::Result<()> {
+    let kind = match err.kind() {
+        ErrorKind::NotImplemented => "not_implemented",
+        ErrorKind::NoAdapter      => "no_adapter",
+        ErrorKind::Hresult(_)     => "hresult",
+        ErrorKind::Win32(_)       => "win32",
+        ErrorKind::NoLastError    => "no_last_error",
+    };
+    write!(w, "{{\"kind\":{}", json_string(kind))?;
+    write!(w, ",\"category\":{}", json_string(err.category().name()))?;
+
+    match err.kind() {
+        ErrorKind::Win32(win32) => write!(w, ",\"win32_code\":{}", win32.0)?,
+        _                       => write!(w, ",\"win32_code\":null")?,
+    }
+
+    match err.call_site() {
+        Some(site) => {
+            write!(w, ",\"file\":{},\"line\":{},\"expression\":{}",
+                   json_string(site.file),
+                   site.line,
+                   json_string(site.expr))?;
+        },
+        None => {
+            write!(w, ",\"file\":null,\"line\":null,\"expression\":null")?;
+        },
+    }
+
+    match err.hresult() {
+        Some(hr) => {
+            let severity = match hr.severity() {
+                Severity::Success => "success",
+                Severity::Failure => "failure",
+            };
+            write!(w, ",\"hresult\":\"0x{:08x}\",\"severity\":{}", hr.0, json_string(severity))?;
+            write!(w, ",\"facility\":{},\"facility_code\":{},\"code\":{}",
+                   json_string(&hr.facility().to_string()),
+                   hr.facility().to_u16(),
+                   hr.code())?;
+            let name = match err.kind() {
+                ErrorKind::Win32(win32) => win32.name(),
+                _                       => hr.name(),
+            };
+            write!(w, ",\"name\":{},\"description\":{},\"message\":{}",
+                   json_option(name),
+                   json_option(hr.description()),
+                   json_option(Some(&*win_error_msg(hr.0)).filter(|msg| !msg.is_empty())))?;
+        },
+        None => {
+            write!(w, ",\"hresult\":null,\"severity\":null")?;
+            write!(w, ",\"facility\":null,\"facility_code\":null,\"code\":null")?;
+            write!(w, ",\"name\":null,\"description\":null,\"message\":{}",
+                   json_string(&err.kind().to_string()))?;
+        },
+    }
+
+    write!(w, ",\"context\":[")?;
+    for (i, context) in err.context_chain().iter().enumerate() {
+        if i != 0 {
+            write!(w, ",")?;
+        }
+        write!(w, "{}", json_string(context))?;
+    }
+    write!(w, "]}}\n")
+}
+
+fn json_option(string: Option<&str>) -> String {
+    match string {
+        Some(string) => json_string(string),
+        None         => "null".to_string(),
+    }
+}
+
+/// Quote and escape `string` for JSON.
+pub fn json_string(string: &str) -> String {
+    use std::fmt::Write;
+
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for c in string.chars() {
+        match c {
+            '"'  => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn write_error<W: termcolor::WriteColor>(w:       &mut W,
+                                             catalog: &ColorSpecCatalog,
+                                             err:     &WindowsError)
+    -> io::Result<()>
+{
+    if let Some(site) = err.call_site() {
+        w.set_color(&catalog.file)?;
+        write!(w, "{}", site.file)?;
+        w.reset()?;
+        write!(w, ":")?;
+        w.set_color(&catalog.line)?;
+        write!(w, "{}", site.line)?;
+        w.reset()?;
+        write!(w, ": ")?;
+    }
+
+    match err.kind() {
+        ErrorKind::Hresult(hr) => {
+            let msg = match hr.name() {
+                Some(name) => Cow::Borrowed(name),
+                None       => win_error_msg(hr.0),
+            };
+            if msg.is_empty() {
+                w.set_color(&catalog.hresult)?;
+                write!(w, "{}", hr)?;
+            } else {
+                w.set_color(&catalog.windows_msg)?;
+                write!(w, "{}", msg)?;
+                w.reset()?;
+                write!(w, " ")?;
+                w.set_color(&catalog.hresult)?;
+                if hr.name().is_some() {
+                    write!(w, "{}", hr.fields())?;
+                } else {
+                    write!(w, "(0x{:08x}) {}", hr.0, hr.fields())?;
+                }
+            }
+            w.reset()?;
+        },
+        ErrorKind::Win32(win32) => {
+            let msg = system_error_msg(win32.to_hresult().0)
+                .map(Cow::Owned)
+                .or_else(|| win32.name().map(Cow::Borrowed))
+                .unwrap_or(Cow::Borrowed("Win32 error"));
+            w.set_color(&catalog.windows_msg)?;
+            write!(w, "{}", msg)?;
+            w.reset()?;
+            write!(w, " ")?;
+            w.set_color(&catalog.hresult)?;
+            write!(w, "({}, HRESULT 0x{:08x})", win32, win32.to_hresult().0)?;
+            w.reset()?;
+        },
+        kind => {
+            w.set_color(&catalog.windows_msg)?;
+            write!(w, "{}", kind)?;
+            w.reset()?;
+        },
+    }
+
+    if let Some(site) = err.call_site() {
+        write!(w, " from:\n    ")?;
+        w.set_color(&catalog.expr)?;
+        write!(w, "{}", site.expr.replace("\n", "\n    "))?;
+        w.reset()?;
+    }
+    write!(w, "\n")?;
+
+    if let Some(description) = err.hresult().and_then(|hr| hr.description()) {
+        w.set_color(&catalog.note)?;
+        write!(w, "  note: {}", description)?;
+        w.reset()?;
+        write!(w, "\n")?;
+    }
+    for context in err.context_chain() {
+        write!(w, "  while {}\n", context)?;
+    }
+
+    Ok(())
+}
+
+/// A Win32 error code, as returned by `GetLastError()`.
+///
+/// These are not `HRESULT`s! Use `to_hresult()` to convert one.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Win32Error(pub u32);
+
+impl Win32Error {
+    /// The calling thread's last error. Read it before calling anything else.
+    #[cfg(windows)]
+    pub fn last() -> Win32Error {
+        use winapi::um::errhandlingapi::GetLastError;
+        Win32Error(unsafe { GetLastError() })
+    }
+
+    /// `HRESULT_FROM_WIN32`
+    // https://docs.microsoft.com/en-us/windows/desktop/api/winerror/nf-winerror-hresult_from_win32
+    pub fn to_hresult(self) -> HResult {
+        if (self.0 as i32) <= 0 {
+            HResult(self.0 as i32)
+        } else {
+            let facility = Facility::Win32.to_u16() as u32;
+            HResult(((self.0 & 0xFFFF) | (facility << 16) | 0x8000_0000) as i32)
+        }
+    }
+
+    /// The symbolic name of this code, for the handful we run into.
+    pub fn name(self) -> Option<&'static str> {
+        match self.0 {
+            0    => Some("ERROR_SUCCESS"),
+            1    => Some("ERROR_INVALID_FUNCTION"),
+            2    => Some("ERROR_FILE_NOT_FOUND"),
+            3    => Some("ERROR_PATH_NOT_FOUND"),
+            5    => Some("ERROR_ACCESS_DENIED"),
+            6    => Some("ERROR_INVALID_HANDLE"),
+            8    => Some("ERROR_NOT_ENOUGH_MEMORY"),
+            14   => Some("ERROR_OUTOFMEMORY"),
+            87   => Some("ERROR_INVALID_PARAMETER"),
+            122  => Some("ERROR_INSUFFICIENT_BUFFER"),
+            126  => Some("ERROR_MOD_NOT_FOUND"),
+            127  => Some("ERROR_PROC_NOT_FOUND"),
+            1004 => Some("ERROR_INVALID_FLAGS"),
+            1400 => Some("ERROR_INVALID_WINDOW_HANDLE"),
+            1407 => Some("ERROR_CANNOT_FIND_WND_CLASS"),
+            1410 => Some("ERROR_CLASS_ALREADY_EXISTS"),
+            1411 => Some("ERROR_CLASS_DOES_NOT_EXIST"),
+            1413 => Some("ERROR_INVALID_INDEX"),
+            1414 => Some("ERROR_INVALID_ICON_HANDLE"),
+            1812 => Some("ERROR_RESOURCE_DATA_NOT_FOUND"),
+            1813 => Some("ERROR_RESOURCE_TYPE_NOT_FOUND"),
+            _    => None,
+        }
+    }
+}
+
+impl fmt::Debug for Win32Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Win32Error({})", self.0)
+    }
+}
+
+impl fmt::Display for Win32Error {
+    /// e.g. `ERROR_CLASS_ALREADY_EXISTS (Win32 error 1410)`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{} (Win32 error {})", name, self.0),
+            None       => write!(f, "Win32 error {}", self.0),
+        }
+    }
+}
+
+/// Values that Win32 functions return, which can tell us if they failed.
+///
+/// Most Win32 functions signal failure with a zero or null return, and leave
+/// the details in `GetLastError()`.
+pub trait Win32Return {
+    fn is_failure(&self) -> bool;
+}
+
+impl Win32Return for u16 {
+    /// e.g. `ATOM`
+    fn is_failure(&self) -> bool { *self == 0 }
+}
+
+impl Win32Return for i32 {
+    /// e.g. `BOOL`
+    fn is_failure(&self) -> bool { *self == 0 }
+}
+
+impl<T> Win32Return for *mut T {
+    /// e.g. `HWND`
+    fn is_failure(&self) -> bool { self.is_null() }
+}
+
+impl<T> Win32Return for *const T {
+    fn is_failure(&self) -> bool { self.is_null() }
+}
+
+/// Write everything we know about `hr`, one field per line.
+pub fn write_hresult_report<W: io::Write>(w: &mut W, hr: HResult) -> io::Result<()> {
+    let facility = hr.facility();
+    let yes_no = |yes| if yes { "yes" } else { "no" };
+
+    writeln!(w, "0x{:08x} ({}):", hr.0, hr.0)?;
+    writeln!(w, "    Name:        {}", hr.name().unwrap_or("<unknown>"))?;
+    writeln!(w, "    Severity:    {:?}", hr.severity())?;
+    writeln!(w, "    Facility:    {} (0x{:x})", facility, facility.to_u16())?;
+    writeln!(w, "    Code:        0x{:x} ({})", hr.code(), hr.code())?;
+    writeln!(w, "    Customer:    {}", yes_no(hr.is_customer()))?;
+    writeln!(w, "    NTSTATUS:    {}", yes_no(hr.is_ntstatus()))?;
+    if let Some(description) = hr.description() {
+        writeln!(w, "    Description: {}", description)?;
+    }
+    if let Some(msg) = dxgi_error_msg(hr.0).filter(|_| hr.name().is_none()) {
+        writeln!(w, "    Note:        {}", msg)?;
+    }
+    if let Some(msg) = system_error_msg(hr.0) {
+        writeln!(w, "    System:      {}", msg)?;
+    }
+    Ok(())
+}
+
+/// An `HRESULT`, broken out into its component fields.
+///
+/// See [the HRESULT docs](https://docs.microsoft.com/en-us/openspecs/windows_protocols/ms-erref/0642cb2f-2075-4469-918c-4441e69c548a)
+/// for the layout:
+///
+/// ```text
+///  3 3 2 2 2 2 2 2 2 2 2 2 1 1 1 1 1 1 1 1 1 1
+///  1 0 9 8 7 6 5 4 3 2 1 0 9 8 7 6 5 4 3 2 1 0 9 8 7 6 5 4 3 2 1 0
+/// +-+-+-+-+-+---------------------+-------------------------------+
+/// |S|R|C|N|X|      Facility       |              Code             |
+/// +-+-+-+-+-+---------------------+-------------------------------+
+/// ```
+///
+/// Note that the graphics facilities (e.g. DXGI is 0x87a) don't fit in 11
+/// bits, so like `HRESULT_FACILITY` in winerror.h, we read 13 bits and let
+/// the facility overlap the N and X bits.
+///
+/// That means N is also bit 12 of the facility. No HRESULT facility goes
+/// that high, so we take N to mean a mapped `NTSTATUS` (`HRESULT_FROM_NT`),
+/// and read its facility from the 12 bits below N instead.
+///
+/// This is all bit math, so none of it needs Windows to run.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HResult(pub i32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Facility {
+    Null,           // 0x000
+    Rpc,            // 0x001
+    Dispatch,       // 0x002
+    Storage,        // 0x003
+    Itf,            // 0x004
+    Win32,          // 0x007
+    Windows,        // 0x008
+    Security,       // 0x009
+    Control,        // 0x00a
+    Internet,       // 0x00c
+    D3D10,          // 0x879
+    Dxgi,           // 0x87a
+    DxgiDdi,        // 0x87b
+    D3D11,          // 0x87c
+    D3D11Debug,     // 0x87d
+    D3D12,          // 0x87e
+    D3D12Debug,     // 0x87f
+    Wincodec,       // 0x898
+    Direct2D,       // 0x899
+    Unknown(u16),
+}
+
+impl Facility {
+    pub fn from_u16(facility: u16) -> Facility {
+        match facility {
+            0x000 => Facility::Null,
+            0x001 => Facility::Rpc,
+            0x002 => Facility::Dispatch,
+            0x003 => Facility::Storage,
+            0x004 => Facility::Itf,
+            0x007 => Facility::Win32,
+            0x008 => Facility::Windows,
+            0x009 => Facility::Security,
+            0x00a => Facility::Control,
+            0x00c => Facility::Internet,
+            0x879 => Facility::D3D10,
+            0x87a => Facility::Dxgi,
+            0x87b => Facility::DxgiDdi,
+            0x87c => Facility::D3D11,
+            0x87d => Facility::D3D11Debug,
+            0x87e => Facility::D3D12,
+            0x87f => Facility::D3D12Debug,
+            0x898 => Facility::Wincodec,
+            0x899 => Facility::Direct2D,
+            other => Facility::Unknown(other),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            Facility::Null          => 0x000,
+            Facility::Rpc           => 0x001,
+            Facility::Dispatch      => 0x002,
+            Facility::Storage       => 0x003,
+            Facility::Itf           => 0x004,
+            Facility::Win32         => 0x007,
+            Facility::Windows       => 0x008,
+            Facility::Security      => 0x009,
+            Facility::Control       => 0x00a,
+            Facility::Internet      => 0x00c,
+            Facility::D3D10         => 0x879,
+            Facility::Dxgi          => 0x87a,
+            Facility::DxgiDdi       => 0x87b,
+            Facility::D3D11         => 0x87c,
+            Facility::D3D11Debug    => 0x87d,
+            Facility::D3D12         => 0x87e,
+            Facility::D3D12Debug    => 0x87f,
+            Facility::Wincodec      => 0x898,
+            Facility::Direct2D      => 0x899,
+            Facility::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for Facility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Facility::Null          => write!(f, "NULL"),
+            Facility::Rpc           => write!(f, "RPC"),
+            Facility::Dispatch      => write!(f, "DISPATCH"),
+            Facility::Storage       => write!(f, "STORAGE"),
+            Facility::Itf           => write!(f, "ITF"),
+            Facility::Win32         => write!(f, "WIN32"),
+            Facility::Windows       => write!(f, "WINDOWS"),
+            Facility::Security      => write!(f, "SECURITY"),
+            Facility::Control       => write!(f, "CONTROL"),
+            Facility::Internet      => write!(f, "INTERNET"),
+            Facility::D3D10         => write!(f, "D3D10"),
+            Facility::Dxgi          => write!(f, "DXGI"),
+            Facility::DxgiDdi       => write!(f, "DXGI_DDI"),
+            Facility::D3D11         => write!(f, "D3D11"),
+            Facility::D3D11Debug    => write!(f, "D3D11_DEBUG"),
+            Facility::D3D12         => write!(f, "D3D12"),
+            Facility::D3D12Debug    => write!(f, "D3D12_DEBUG"),
+            Facility::Wincodec      => write!(f, "WINCODEC"),
+            Facility::Direct2D      => write!(f, "DIRECT2D"),
+            Facility::Unknown(_)    => write!(f, "UNKNOWN"),
+        }
     }
 }
 
-fn hresult_code(hresult: HRESULT) -> u32 {
+impl HResult {
+    /// Bit 31. Set for failures.
+    pub fn severity(self) -> Severity {
+        if self.0 < 0 { Severity::Failure } else { Severity::Success }
+    }
+
+    pub fn succeeded(self) -> bool {
+        self.severity() == Severity::Success
+    }
+
+    pub fn failed(self) -> bool {
+        self.severity() == Severity::Failure
+    }
+
+    /// Bit 29. Set for customer-defined (i.e. not Microsoft) values.
+    pub fn is_customer(self) -> bool {
+        (self.0 as u32) & (1 << 29) != 0
+    }
+
+    /// Bit 28. Set when this is a mapped `NTSTATUS` value.
+    ///
+    /// This is also the top bit of a 13-bit facility, but no facility is
+    /// that large, so only `HRESULT_FROM_NT` sets it.
+    pub fn is_ntstatus(self) -> bool {
+        (self.0 as u32) & (1 << 28) != 0
+    }
+
+    /// Bits 16-28, or for a mapped `NTSTATUS`, its own facility in bits
+    /// 16-27.
+    pub fn facility(self) -> Facility {
+        let mask = if self.is_ntstatus() { 0xFFF } else { 0x1FFF };
+        Facility::from_u16((((self.0 as u32) >> 16) & mask) as u16)
+    }
+
+    /// Bits 0-15.
     // https://docs.microsoft.com/en-us/windows/desktop/api/winerror/nf-winerror-hresult_code
-    (hresult as u32) & 0xFFFF
+    pub fn code(self) -> u16 {
+        ((self.0 as u32) & 0xFFFF) as u16
+    }
+
+    /// Whether this means the device is gone and has to be recreated.
+    pub fn is_device_lost(self) -> bool {
+        match self.0 as u32 {
+            0x887A0005 | // DXGI_ERROR_DEVICE_REMOVED
+            0x887A0006 | // DXGI_ERROR_DEVICE_HUNG
+            0x887A0007 | // DXGI_ERROR_DEVICE_RESET
+            0x887A0020   // DXGI_ERROR_DRIVER_INTERNAL_ERROR
+                => true,
+            _   => false,
+        }
+    }
+
+    /// The symbolic name of this value, if we know it.
+    pub fn name(self) -> Option<&'static str> {
+        lookup_error(self.0).map(|info| info.name)
+    }
+
+    /// The documented meaning of this value, if we know it.
+    pub fn description(self) -> Option<&'static str> {
+        lookup_error(self.0).map(|info| info.description)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidHResult;
+
+impl ::std::str::FromStr for HResult {
+    type Err = InvalidHResult;
+
+    /// Accepts hex (`0x887A0005`), unsigned decimal (`2289696773`), and
+    /// signed decimal (`-2005270523`), since logs use all three.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let string = string.trim();
+        let value = if string.starts_with("0x") || string.starts_with("0X") {
+            u32::from_str_radix(&string[2..], 16).map(|value| value as i32)
+        } else if string.starts_with('-') {
+            string.parse::<i32>()
+        } else {
+            string.parse::<u32>().map(|value| value as i32)
+        };
+        value.map(HResult).map_err(|_| InvalidHResult)
+    }
+}
+
+impl From<HRESULT> for HResult {
+    fn from(hresult: HRESULT) -> HResult {
+        HResult(hresult)
+    }
+}
+
+impl fmt::Debug for HResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HResult(0x{:08x})", self.0)
+    }
+}
+
+impl fmt::Display for HResult {
+    /// e.g. `DXGI_ERROR_DEVICE_REMOVED (facility DXGI 0x87a, code 0x5)`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name)?,
+            None       => write!(f, "0x{:08x}", self.0)?,
+        }
+        write!(f, " {}", self.fields())
+    }
+}
+
+/// Displays the facility and code of an `HResult`, without its name.
+pub struct HResultFields(HResult);
+
+impl HResult {
+    pub fn fields(self) -> HResultFields {
+        HResultFields(self)
+    }
+}
+
+impl fmt::Display for HResultFields {
+    /// e.g. `(facility DXGI 0x87a, code 0x5)`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let facility = self.0.facility();
+        write!(f, "(facility {} 0x{:x}, code 0x{:x})",
+               facility,
+               facility.to_u16(),
+               self.0.code())
+    }
+}
+
+/// A known `HRESULT` value, its symbolic name, and what it means.
+#[derive(Debug, Copy, Clone)]
+pub struct ErrorInfo {
+    pub value:       u32,
+    pub name:        &'static str,
+    pub description: &'static str,
+}
+
+impl ErrorInfo {
+    pub fn hresult(&self) -> HResult {
+        HResult(self.value as i32)
+    }
+}
+
+macro_rules! error_table {
+    ($($value:expr => $name:expr, $description:expr;)*) => {
+        &[$(ErrorInfo { value: $value, name: $name, description: $description },)*]
+    }
+}
+
+// Descriptions are lifted from the MSDN pages for each set of return codes:
+//      https://docs.microsoft.com/en-us/windows/desktop/direct3d12/d3d12-graphics-reference-returnvalues
+//      https://docs.microsoft.com/en-us/windows/desktop/direct3ddxgi/dxgi-error
+//      https://docs.microsoft.com/en-us/windows/desktop/direct3ddxgi/dxgi-status
+//      https://docs.microsoft.com/en-us/windows/desktop/direct3d11/d3d11-graphics-reference-returnvalues
+//      https://docs.microsoft.com/en-us/windows/desktop/wic/-wic-codec-error-codes
+static ERROR_TABLE: &[ErrorInfo] = error_table! {
+    // ---- Generic --------
+    0x00000000 => "S_OK",
+        "The operation completed successfully.";
+    0x00000001 => "S_FALSE",
+        "The operation completed successfully, but with a non-standard result.";
+    0x8000FFFF => "E_UNEXPECTED",
+        "Catastrophic failure.";
+    0x80004001 => "E_NOTIMPL",
+        "The method is not implemented.";
+    0x8007000E => "E_OUTOFMEMORY",
+        "Direct3D could not allocate sufficient memory to complete the call.";
+    0x80070057 => "E_INVALIDARG",
+        "An invalid parameter was passed to the returning function.";
+    0x80004002 => "E_NOINTERFACE",
+        "The requested interface is not supported.";
+    0x80004003 => "E_POINTER",
+        "An invalid (usually null) pointer was passed.";
+    0x80070006 => "E_HANDLE",
+        "An invalid handle was passed.";
+    0x80004004 => "E_ABORT",
+        "The operation was aborted.";
+    0x80004005 => "E_FAIL",
+        "Unspecified failure. From D3D12CreateDevice, this usually means the debug layer was requested but is not installed.";
+    0x80070005 => "E_ACCESSDENIED",
+        "A general access-denied error.";
+    0x8000000A => "E_PENDING",
+        "The data necessary to complete this operation is not yet available.";
+    0x8000000B => "E_BOUNDS",
+        "The operation attempted to access data outside the valid range.";
+    0x8000000C => "E_CHANGED_STATE",
+        "A concurrent or interleaved operation changed the state of the object, invalidating this operation.";
+    0x8000000D => "E_ILLEGAL_STATE_CHANGE",
+        "An illegal state change was requested.";
+    0x8000000E => "E_ILLEGAL_METHOD_CALL",
+        "A method was called at an unexpected time.";
+    0x80000017 => "E_STRING_NOT_NULL_TERMINATED",
+        "A string was not NULL terminated where it was expected to be.";
+    0x80000018 => "E_ILLEGAL_DELEGATE_ASSIGNMENT",
+        "A delegate was assigned when not allowed.";
+    0x80000019 => "E_ASYNC_OPERATION_NOT_STARTED",
+        "An async operation was not properly started.";
+    0x8000001A => "E_APPLICATION_EXITING",
+        "The application is exiting and cannot service this request.";
+    0x8000001B => "E_APPLICATION_VIEW_EXITING",
+        "The application view is exiting and cannot service this request.";
+
+    // ---- D3D12 --------
+    0x887E0001 => "D3D12_ERROR_ADAPTER_NOT_FOUND",
+        "The specified cached PSO was created on a different adapter and cannot be reused on the current adapter.";
+    0x887E0002 => "D3D12_ERROR_DRIVER_VERSION_MISMATCH",
+        "The specified cached PSO was created on a different driver version and cannot be reused on the current adapter.";
+
+    // ---- D3D11 / D3D10 --------
+    0x887C0001 => "D3D11_ERROR_TOO_MANY_UNIQUE_STATE_OBJECTS",
+        "There are too many unique instances of a particular type of state object.";
+    0x887C0002 => "D3D11_ERROR_FILE_NOT_FOUND",
+        "The file was not found.";
+    0x887C0003 => "D3D11_ERROR_TOO_MANY_UNIQUE_VIEW_OBJECTS",
+        "There are too many unique instances of a particular type of view object.";
+    0x887C0004 => "D3D11_ERROR_DEFERRED_CONTEXT_MAP_WITHOUT_INITIAL_DISCARD",
+        "The first call to Map after creating or recording on a deferred context was not D3D11_MAP_WRITE_DISCARD.";
+    0x88790001 => "D3D10_ERROR_TOO_MANY_UNIQUE_STATE_OBJECTS",
+        "There are too many unique instances of a particular type of state object.";
+    0x88790002 => "D3D10_ERROR_FILE_NOT_FOUND",
+        "The file was not found.";
+    0x8876086C => "D3DERR_INVALIDCALL",
+        "The method call is invalid. For example, a method's parameter may not be a valid pointer.";
+    0x8876021C => "D3DERR_WASSTILLDRAWING",
+        "The previous blit operation that is transferring information to or from this surface is incomplete.";
+
+    // ---- DXGI errors --------
+    0x887A0001 => "DXGI_ERROR_INVALID_CALL",
+        "The application provided invalid parameter data; this must be debugged and fixed before the application is released.";
+    0x887A0002 => "DXGI_ERROR_NOT_FOUND",
+        "When calling IDXGIObject::GetPrivateData, the GUID passed in is not recognized, or an enumerated index was out of range.";
+    0x887A0003 => "DXGI_ERROR_MORE_DATA",
+        "The buffer supplied by the application is not big enough to hold the requested data.";
+    0x887A0004 => "DXGI_ERROR_UNSUPPORTED",
+        "The requested functionality is not supported by the device or the driver.";
+    0x887A0005 => "DXGI_ERROR_DEVICE_REMOVED",
+        "The video card has been physically removed, a driver upgrade occurred, or the GPU faulted. Call GetDeviceRemovedReason and recreate the device.";
+    0x887A0006 => "DXGI_ERROR_DEVICE_HUNG",
+        "The application's device failed due to badly formed commands sent by the application. This is a design-time issue that should be investigated and fixed.";
+    0x887A0007 => "DXGI_ERROR_DEVICE_RESET",
+        "The device failed due to a badly formed command. This is a run-time issue; the application should destroy and recreate the device.";
+    0x887A000A => "DXGI_ERROR_WAS_STILL_DRAWING",
+        "The GPU was busy at the moment when a call was made to perform an operation, and did not execute or schedule the operation.";
+    0x887A000B => "DXGI_ERROR_FRAME_STATISTICS_DISJOINT",
+        "An event (for example, a power cycle) interrupted the gathering of presentation statistics.";
+    0x887A000C => "DXGI_ERROR_GRAPHICS_VIDPN_SOURCE_IN_USE",
+        "The application attempted to acquire exclusive ownership of an output, but failed because some other application already acquired ownership.";
+    0x887A0020 => "DXGI_ERROR_DRIVER_INTERNAL_ERROR",
+        "The driver encountered a problem and was put into the device removed state.";
+    0x887A0021 => "DXGI_ERROR_NONEXCLUSIVE",
+        "A global counter resource is in use, and the Direct3D device can't currently use the counter resource.";
+    0x887A0022 => "DXGI_ERROR_NOT_CURRENTLY_AVAILABLE",
+        "The resource or request is not currently available, but it might become available later.";
+    0x887A0023 => "DXGI_ERROR_REMOTE_CLIENT_DISCONNECTED",
+        "The remote device has been removed.";
+    0x887A0024 => "DXGI_ERROR_REMOTE_OUTOFMEMORY",
+        "The remote device has run out of memory.";
+    0x887A0025 => "DXGI_ERROR_MODE_CHANGE_IN_PROGRESS",
+        "A mode change is in progress; try the operation again later.";
+    0x887A0026 => "DXGI_ERROR_ACCESS_LOST",
+        "The desktop duplication interface is invalid, usually because the desktop was switched. Recreate it.";
+    0x887A0027 => "DXGI_ERROR_WAIT_TIMEOUT",
+        "The time-out interval elapsed before the next desktop frame was available.";
+    0x887A0028 => "DXGI_ERROR_SESSION_DISCONNECTED",
+        "The Remote Desktop Services session is currently disconnected.";
+    0x887A0029 => "DXGI_ERROR_RESTRICT_TO_OUTPUT_STALE",
+        "The DXGI output (monitor) to which the swap chain content was restricted is now disconnected or changed.";
+    0x887A002A => "DXGI_ERROR_CANNOT_PROTECT_CONTENT",
+        "DXGI can't provide content protection on the swap chain, typically because an older driver is installed.";
+    0x887A002B => "DXGI_ERROR_ACCESS_DENIED",
+        "You tried to use a resource to which you did not have the required access privileges.";
+    0x887A002C => "DXGI_ERROR_NAME_ALREADY_EXISTS",
+        "The supplied name of a resource in a call to IDXGIResource1::CreateSharedHandle is already associated with some other resource.";
+    0x887A002D => "DXGI_ERROR_SDK_COMPONENT_MISSING",
+        "The operation depends on an SDK component that is missing or mismatched.";
+    0x887A002E => "DXGI_ERROR_NOT_CURRENT",
+        "The DXGI objects that the application has created are no longer current and need to be recreated.";
+    0x887A0030 => "DXGI_ERROR_HW_PROTECTION_OUTOFMEMORY",
+        "Insufficient HW protected memory exits for proper function.";
+    0x887A0031 => "DXGI_ERROR_DYNAMIC_CODE_POLICY_VIOLATION",
+        "Creating this device would violate the process's dynamic code policy.";
+    0x887A0032 => "DXGI_ERROR_NON_COMPOSITED_UI",
+        "The operation failed because the compositor is not in control of the output.";
+
+    // ---- DXGI status (these are successes) --------
+    0x087A0001 => "DXGI_STATUS_OCCLUDED",
+        "The window content is not visible. Stop rendering until the window is visible again.";
+    0x087A0002 => "DXGI_STATUS_CLIPPED",
+        "The output was clipped.";
+    0x087A0004 => "DXGI_STATUS_NO_REDIRECTION",
+        "No redirection is in effect.";
+    0x087A0005 => "DXGI_STATUS_NO_DESKTOP_ACCESS",
+        "The desktop is not accessible.";
+    0x087A0006 => "DXGI_STATUS_GRAPHICS_VIDPN_SOURCE_IN_USE",
+        "Another application already owns the output.";
+    0x087A0007 => "DXGI_STATUS_MODE_CHANGED",
+        "The desktop display mode has been changed; there might be color conversion or stretching. Check the output mode.";
+    0x087A0008 => "DXGI_STATUS_MODE_CHANGE_IN_PROGRESS",
+        "A mode change is in progress. The swap chain could not switch to fullscreen; try again later.";
+    0x087A0009 => "DXGI_STATUS_UNOCCLUDED",
+        "The window content is visible again. Rendering can resume.";
+    0x087A000A => "DXGI_STATUS_DDA_WAS_STILL_DRAWING",
+        "The desktop duplication frame was still being drawn.";
+    0x087A002F => "DXGI_STATUS_PRESENT_REQUIRED",
+        "The swap chain must be presented before further operations can succeed.";
+
+    // ---- WIC --------
+    0x88982F04 => "WINCODEC_ERR_WRONGSTATE",
+        "The codec is in the wrong state.";
+    0x88982F05 => "WINCODEC_ERR_VALUEOUTOFRANGE",
+        "The value is out of range.";
+    0x88982F07 => "WINCODEC_ERR_UNKNOWNIMAGEFORMAT",
+        "The image format is unknown.";
+    0x88982F0B => "WINCODEC_ERR_UNSUPPORTEDVERSION",
+        "The SDK version is unsupported.";
+    0x88982F0C => "WINCODEC_ERR_NOTINITIALIZED",
+        "The component is not initialized.";
+    0x88982F40 => "WINCODEC_ERR_PROPERTYNOTFOUND",
+        "The property could not be found.";
+    0x88982F48 => "WINCODEC_ERR_INTERNALERROR",
+        "An internal error occurred.";
+    0x88982F50 => "WINCODEC_ERR_COMPONENTNOTFOUND",
+        "The component cannot be found.";
+    0x88982F51 => "WINCODEC_ERR_IMAGESIZEOUTOFRANGE",
+        "The bitmap size is outside the valid range.";
+    0x88982F60 => "WINCODEC_ERR_BADIMAGE",
+        "The image is unrecognized or corrupt.";
+    0x88982F61 => "WINCODEC_ERR_BADHEADER",
+        "The image header is unrecognized.";
+    0x88982F62 => "WINCODEC_ERR_FRAMEMISSING",
+        "The bitmap frame is missing.";
+    0x88982F70 => "WINCODEC_ERR_BADSTREAMDATA",
+        "The stream data is corrupt.";
+    0x88982F80 => "WINCODEC_ERR_UNSUPPORTEDPIXELFORMAT",
+        "The bitmap pixel format is unsupported.";
+    0x88982F81 => "WINCODEC_ERR_UNSUPPORTEDOPERATION",
+        "The operation is unsupported.";
+    0x88982F8C => "WINCODEC_ERR_INSUFFICIENTBUFFER",
+        "The buffer is too small.";
+};
+
+/// Look up the name and description of a known `HRESULT`.
+pub fn lookup_error(hresult: HRESULT) -> Option<&'static ErrorInfo> {
+    ERROR_TABLE.iter().find(|info| info.value == hresult as u32)
 }
 
 pub fn dxgi_error_msg(hresult: HRESULT) -> Option<&'static str> {
-    match hresult {
-        winerror::DXGI_ERROR_ACCESS_DENIED                => Some("DXGI_ERROR_ACCESS_DENIED"),
-        winerror::DXGI_ERROR_ACCESS_LOST                  => Some("DXGI_ERROR_ACCESS_LOST"),
-        winerror::DXGI_ERROR_CANNOT_PROTECT_CONTENT       => Some("DXGI_ERROR_CANNOT_PROTECT_CONTENT"),
-        winerror::DXGI_ERROR_DEVICE_HUNG                  => Some("DXGI_ERROR_DEVICE_HUNG"),
-        winerror::DXGI_ERROR_DEVICE_REMOVED               => Some("DXGI_ERROR_DEVICE_REMOVED"),
-        winerror::DXGI_ERROR_DEVICE_RESET                 => Some("DXGI_ERROR_DEVICE_RESET"),
-        winerror::DXGI_ERROR_DRIVER_INTERNAL_ERROR        => Some("DXGI_ERROR_DRIVER_INTERNAL_ERROR"),
-        winerror::DXGI_ERROR_FRAME_STATISTICS_DISJOINT    => Some("DXGI_ERROR_FRAME_STATISTICS_DISJOINT"),
-        winerror::DXGI_ERROR_GRAPHICS_VIDPN_SOURCE_IN_USE => Some("DXGI_ERROR_GRAPHICS_VIDPN_SOURCE_IN_USE"),
-        winerror::DXGI_ERROR_INVALID_CALL                 => Some("DXGI_ERROR_INVALID_CALL"),
-        winerror::DXGI_ERROR_MORE_DATA                    => Some("DXGI_ERROR_MORE_DATA"),
-        winerror::DXGI_ERROR_NAME_ALREADY_EXISTS          => Some("DXGI_ERROR_NAME_ALREADY_EXISTS"),
-        winerror::DXGI_ERROR_NONEXCLUSIVE                 => Some("DXGI_ERROR_NONEXCLUSIVE"),
-        winerror::DXGI_ERROR_NOT_CURRENTLY_AVAILABLE      => Some("DXGI_ERROR_NOT_CURRENTLY_AVAILABLE"),
-        winerror::DXGI_ERROR_NOT_FOUND                    => Some("DXGI_ERROR_NOT_FOUND"),
-        winerror::DXGI_ERROR_REMOTE_CLIENT_DISCONNECTED   => Some("DXGI_ERROR_REMOTE_CLIENT_DISCONNECTED"),
-        winerror::DXGI_ERROR_REMOTE_OUTOFMEMORY           => Some("DXGI_ERROR_REMOTE_OUTOFMEMORY"),
-        winerror::DXGI_ERROR_RESTRICT_TO_OUTPUT_STALE     => Some("DXGI_ERROR_RESTRICT_TO_OUTPUT_STALE"),
-        winerror::DXGI_ERROR_SDK_COMPONENT_MISSING        => Some("DXGI_ERROR_SDK_COMPONENT_MISSING"),
-        winerror::DXGI_ERROR_SESSION_DISCONNECTED         => Some("DXGI_ERROR_SESSION_DISCONNECTED"),
-        winerror::DXGI_ERROR_UNSUPPORTED                  => Some("DXGI_ERROR_UNSUPPORTED"),
-        winerror::DXGI_ERROR_WAIT_TIMEOUT                 => Some("DXGI_ERROR_WAIT_TIMEOUT"),
-        winerror::DXGI_ERROR_WAS_STILL_DRAWING            => Some("DXGI_ERROR_WAS_STILL_DRAWING"),
-        code if (code as u32 >> 16) == 0x887a             => Some("Unknown DXGI_ERROR"),
-        _                                                 => None,
+    let decoded = HResult(hresult);
+    if decoded.facility() != Facility::Dxgi {
+        return None;
+    }
+    match lookup_error(hresult) {
+        Some(info)                  => Some(info.name),
+        None if decoded.failed()    => Some("Unknown DXGI_ERROR"),
+        None                        => Some("Unknown DXGI_STATUS"),
     }
 }
 
 pub fn generic_error_msg(hresult: HRESULT) -> Option<&'static str> {
-    match hresult {
-        winerror::E_UNEXPECTED                  => Some("E_UNEXPECTED"),
-        winerror::E_NOTIMPL                     => Some("E_NOTIMPL"),
-        winerror::E_OUTOFMEMORY                 => Some("E_OUTOFMEMORY"),
-        winerror::E_INVALIDARG                  => Some("E_INVALIDARG"),
-        winerror::E_NOINTERFACE                 => Some("E_NOINTERFACE"),
-        winerror::E_POINTER                     => Some("E_POINTER"),
-        winerror::E_HANDLE                      => Some("E_HANDLE"),
-        winerror::E_ABORT                       => Some("E_ABORT"),
-        winerror::E_FAIL                        => Some("E_FAIL"),
-        winerror::E_ACCESSDENIED                => Some("E_ACCESSDENIED"),
-        winerror::E_PENDING                     => Some("E_PENDING"),
-        winerror::E_BOUNDS                      => Some("E_BOUNDS"),
-        winerror::E_CHANGED_STATE               => Some("E_CHANGED_STATE"),
-        winerror::E_ILLEGAL_STATE_CHANGE        => Some("E_ILLEGAL_STATE_CHANGE"),
-        winerror::E_ILLEGAL_METHOD_CALL         => Some("E_ILLEGAL_METHOD_CALL"),
-        winerror::E_STRING_NOT_NULL_TERMINATED  => Some("E_STRING_NOT_NULL_TERMINATED"),
-        winerror::E_ILLEGAL_DELEGATE_ASSIGNMENT => Some("E_ILLEGAL_DELEGATE_ASSIGNMENT"),
-        winerror::E_ASYNC_OPERATION_NOT_STARTED => Some("E_ASYNC_OPERATION_NOT_STARTED"),
-        winerror::E_APPLICATION_EXITING         => Some("E_APPLICATION_EXITING"),
-        winerror::E_APPLICATION_VIEW_EXITING    => Some("E_APPLICATION_VIEW_EXITING"),
-        _                                       => None,
-    }
-}
-
-pub fn win_error_msg(hresult: HRESULT) -> &'static str {
+    lookup_error(hresult).map(|info| info.name)
+}
+
+/// The best message we have for `hresult`.
+///
+/// Values in our tables return their symbolic name. Anything else is handed
+/// to `FormatMessageW` on Windows. If nobody knows about the value, this
+/// returns an empty string.
+pub fn win_error_msg(hresult: HRESULT) -> Cow<'static, str> {
+    if let Some(error_msg) = dxgi_error_msg(hresult) {
+        return Cow::Borrowed(error_msg);
+    }
+    if let Some(error_msg) = generic_error_msg(hresult) {
+        return Cow::Borrowed(error_msg);
+    }
+    match system_error_msg(hresult) {
+        Some(error_msg) => Cow::Owned(error_msg),
+        None            => Cow::Borrowed(""),
+    }
+}
+
+/// The message `FormatMessage` has for `hresult`, if any.
+#[cfg(windows)]
+pub fn system_error_msg(hresult: HRESULT) -> Option<String> {
+    use std::{
+        ptr,
+        slice,
+    };
     use winapi::um::winbase::{
-        FormatMessageA,
+        FormatMessageW,
+        LocalFree,
+        FORMAT_MESSAGE_ALLOCATE_BUFFER,
         FORMAT_MESSAGE_FROM_SYSTEM,
         FORMAT_MESSAGE_IGNORE_INSERTS,
     };
@@ -109,44 +1179,64 @@ pub fn win_error_msg(hresult: HRESULT) -> &'static str {
         SUBLANG_DEFAULT,
     };
 
-    if let Some(error_msg) = dxgi_error_msg(hresult) {
-        return error_msg;
-    }
-    if let Some(error_msg) = generic_error_msg(hresult) {
-        return error_msg;
-    }
+    // Win32 errors are looked up by their code, everything else by the
+    // full value.
+    let decoded = HResult(hresult);
+    let message_id = match decoded.facility() {
+        Facility::Win32 => decoded.code() as u32,
+        _               => hresult as u32,
+    };
 
-    static mut BUFFER: [u8; 128] = [0u8; 128];
     unsafe {
-        // Fill the buffer so that our trim below can actually trim it.
-        // Reminder: We're converting from C-style NULL-terminated strings to
-        //           Rust's UTF8 strings.
-        // We do this every call to clear the previous error message.
-        ptr::write_bytes(BUFFER.as_mut_ptr(), 0u8, BUFFER.len());
-        let _n = FormatMessageA(FORMAT_MESSAGE_FROM_SYSTEM |
-                                FORMAT_MESSAGE_IGNORE_INSERTS,
-                                ptr::null_mut(),
-                                hresult_code(hresult),
-                                MAKELANGID(LANG_NEUTRAL, SUBLANG_DEFAULT) as u32,
-                                BUFFER.as_mut_ptr() as *mut _,
-                                BUFFER.len() as u32,
-                                ptr::null_mut());
-        str::from_utf8(&BUFFER)
-            // We do not expect this message to be malformed.
-            // If it is, we have bigger problems than what this is reporting.
-            .unwrap()
-            // These messages often end in "\n\r\0", followed by NULLs for
-            // the rest of the buffer.
-            .trim_matches(|c| { c == '\n' || c == '\r' || c == '\0' })
+        // With FORMAT_MESSAGE_ALLOCATE_BUFFER, the system allocates a buffer
+        // big enough for the whole message and writes its address through
+        // the lpBuffer argument. We own it and free it with LocalFree.
+        let mut buffer: *mut u16 = ptr::null_mut();
+        let len = FormatMessageW(FORMAT_MESSAGE_ALLOCATE_BUFFER |
+                                 FORMAT_MESSAGE_FROM_SYSTEM |
+                                 FORMAT_MESSAGE_IGNORE_INSERTS,
+                                 ptr::null_mut(),
+                                 message_id,
+                                 MAKELANGID(LANG_NEUTRAL, SUBLANG_DEFAULT) as u32,
+                                 &mut buffer as *mut *mut u16 as *mut u16,
+                                 0,
+                                 ptr::null_mut());
+        if len == 0 || buffer.is_null() {
+            return None;
+        }
+
+        // Don't trust the system to give us valid UTF-16.
+        let error_msg = String::from_utf16_lossy(slice::from_raw_parts(buffer,
+                                                                       len as usize));
+        LocalFree(buffer as *mut _);
+
+        // These messages often end in "\r\n".
+        let error_msg = error_msg.trim_matches(|c| {
+            c == '\n' || c == '\r' || c == '\0'
+        });
+        if error_msg.is_empty() {
+            None
+        } else {
+            Some(error_msg.to_string())
+        }
     }
 }
 
+#[cfg(not(windows))]
+pub fn system_error_msg(_hresult: HRESULT) -> Option<String> {
+    // No FormatMessage here: the tables are all we have.
+    None
+}
+
+/// Colors for each part of an error message.
 pub struct ColorSpecCatalog {
     pub file:        termcolor::ColorSpec,
     pub line:        termcolor::ColorSpec,
     pub func:        termcolor::ColorSpec,
+    pub expr:        termcolor::ColorSpec,
     pub windows_msg: termcolor::ColorSpec,
     pub hresult:     termcolor::ColorSpec,
+    pub note:        termcolor::ColorSpec,
 }
 
 pub fn get_color_spec_catalog() -> ColorSpecCatalog {
@@ -155,23 +1245,414 @@ pub fn get_color_spec_catalog() -> ColorSpecCatalog {
         ColorSpec,
     };
 
-    // TODO: It would be neat if this were loaded from a config file.
     let mut specs = ColorSpecCatalog {
         file:        ColorSpec::new(),
         line:        ColorSpec::new(),
         func:        ColorSpec::new(),
+        expr:        ColorSpec::new(),
         windows_msg: ColorSpec::new(),
         hresult:     ColorSpec::new(),
+        note:        ColorSpec::new(),
     };
 
     specs.file.set_fg(None);
     specs.line.set_fg(None);
     specs.func.set_fg(None);
 
+    specs.expr.set_fg(Some(Color::Green));
+    specs.expr.set_intense(true);
+
     specs.windows_msg.set_fg(Some(Color::Red));
     specs.windows_msg.set_intense(true);
 
     specs.hresult.set_fg(Some(Color::Red));
 
+    specs.note.set_fg(Some(Color::Cyan));
+
     specs
 }
+
+/// A problem with a theme file.
+#[derive(Debug)]
+pub struct ThemeError {
+    pub path:    PathBuf,
+    /// 1-based, if the problem is with a specific line.
+    pub line:    Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ThemeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None       => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// Load a `ColorSpecCatalog` from a theme file.
+///
+/// A theme file has one `part = spec` per line, and `#` starts a comment:
+///
+/// ```text
+/// # Parts are file, line, func, expr, windows_msg, hresult, and note.
+/// windows_msg = fg:yellow bold
+/// hresult     = 208
+/// note        = none
+/// ```
+///
+/// A spec is a list of `bold`, `intense`, `underline`, `fg:COLOR`, `bg:COLOR`
+/// or a bare `COLOR` for the foreground. `none` clears the style.
+/// Colors are anything `termcolor` can parse: names, ANSI 256 numbers, or
+/// `r,g,b` triples. Parts not mentioned keep their default colors.
+pub fn load_color_spec_catalog(path: &Path) -> Result<ColorSpecCatalog, ThemeError> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|err| ThemeError {
+            path:    path.to_path_buf(),
+            line:    None,
+            message: err.to_string(),
+        })?;
+
+    parse_color_spec_catalog(&text).map_err(|(line, message)| ThemeError {
+        path: path.to_path_buf(),
+        line: Some(line),
+        message,
+    })
+}
+
+/// Parse the contents of a theme file. Errors are `(line, message)`.
+pub fn parse_color_spec_catalog(text: &str) -> Result<ColorSpecCatalog, (usize, String)> {
+    let mut specs = get_color_spec_catalog();
+
+    for (line, i) in text.lines().zip(1..) {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None          => line,
+        }.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut halves = line.splitn(2, '=');
+        let part = halves.next().unwrap_or("").trim();
+        let spec = match halves.next() {
+            Some(spec) => parse_color_spec(spec).map_err(|message| (i, message))?,
+            None       => return Err((i, format!("Expected `part = spec`, found \"{}\"", line))),
+        };
+
+        match part {
+            "file"        => specs.file        = spec,
+            "line"        => specs.line        = spec,
+            "func"        => specs.func        = spec,
+            "expr"        => specs.expr        = spec,
+            "windows_msg" => specs.windows_msg = spec,
+            "hresult"     => specs.hresult     = spec,
+            "note"        => specs.note        = spec,
+            _             => return Err((i, format!("Unknown part \"{}\"", part))),
+        }
+    }
+
+    Ok(specs)
+}
+
+fn parse_color_spec(text: &str) -> Result<termcolor::ColorSpec, String> {
+    use termcolor::{
+        Color,
+        ColorSpec,
+    };
+
+    fn parse_color(color: &str) -> Result<Color, String> {
+        color.parse::<Color>().map_err(|err| err.to_string())
+    }
+
+    let mut spec = ColorSpec::new();
+    for word in text.split_whitespace() {
+        match word {
+            "none"      => { spec.clear(); },
+            "bold"      => { spec.set_bold(true); },
+            "intense"   => { spec.set_intense(true); },
+            "underline" => { spec.set_underline(true); },
+            _ if word.starts_with("fg:") => { spec.set_fg(Some(parse_color(&word[3..])?)); },
+            _ if word.starts_with("bg:") => { spec.set_bg(Some(parse_color(&word[3..])?)); },
+            _           => { spec.set_fg(Some(parse_color(word)?)); },
+        }
+    }
+    Ok(spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_dxgi_device_removed() {
+        let hr = HResult(0x887A0005u32 as i32);
+        assert_eq!(hr.severity(), Severity::Failure);
+        assert!(hr.failed());
+        assert!(!hr.is_customer());
+        assert!(!hr.is_ntstatus());
+        assert_eq!(hr.facility(), Facility::Dxgi);
+        assert_eq!(hr.code(), 5);
+        assert_eq!(hr.name(), Some("DXGI_ERROR_DEVICE_REMOVED"));
+        assert!(hr.is_device_lost());
+        assert_eq!(hr.fields().to_string(), "(facility DXGI 0x87a, code 0x5)");
+    }
+
+    #[test]
+    fn displays_name_and_fields() {
+        assert_eq!(HResult(0x887A0005u32 as i32).to_string(),
+                   "DXGI_ERROR_DEVICE_REMOVED (facility DXGI 0x87a, code 0x5)");
+        assert_eq!(HResult(0x80070005u32 as i32).to_string(),
+                   "E_ACCESSDENIED (facility WIN32 0x7, code 0x5)");
+        assert_eq!(HResult(0x81230001u32 as i32).to_string(),
+                   "0x81230001 (facility UNKNOWN 0x123, code 0x1)");
+    }
+
+    #[test]
+    fn decodes_win32_and_success_values() {
+        // E_ACCESSDENIED
+        let hr = HResult(0x80070005u32 as i32);
+        assert_eq!(hr.facility(), Facility::Win32);
+        assert_eq!(hr.code(), 5);
+        assert!(!hr.is_device_lost());
+
+        // S_FALSE
+        let hr = HResult(1);
+        assert_eq!(hr.severity(), Severity::Success);
+        assert!(hr.succeeded());
+        assert_eq!(hr.facility(), Facility::Null);
+        assert_eq!(hr.code(), 1);
+    }
+
+    #[test]
+    fn decodes_customer_bit() {
+        let hr = HResult(0xA0040200u32 as i32);
+        assert!(hr.is_customer());
+        assert!(!hr.is_ntstatus());
+        assert_eq!(hr.facility(), Facility::Itf);
+        assert_eq!(hr.code(), 0x200);
+    }
+
+    #[test]
+    fn decodes_mapped_ntstatus() {
+        // HRESULT_FROM_NT(STATUS_ACCESS_VIOLATION)
+        let hr = HResult(0xD0000005u32 as i32);
+        assert!(hr.failed());
+        assert!(hr.is_ntstatus());
+        assert!(!hr.is_customer());
+        assert_eq!(hr.facility(), Facility::Null);
+        assert_eq!(hr.code(), 5);
+
+        // No real facility has bit 12 set, so this is the only way N is set.
+        assert!(!HResult(0x887E0001u32 as i32).is_ntstatus());
+    }
+
+    #[test]
+    fn unknown_facilities_round_trip() {
+        let hr = HResult(0x81230001u32 as i32);
+        assert_eq!(hr.facility(), Facility::Unknown(0x123));
+        assert_eq!(hr.facility().to_u16(), 0x123);
+        for code in &[0x000, 0x007, 0x87a, 0x87e, 0x899] {
+            assert_eq!(Facility::from_u16(*code).to_u16(), *code);
+        }
+    }
+
+    #[test]
+    fn parses_hex_and_decimal() {
+        let expected = HResult(0x887A0005u32 as i32);
+        for string in &["0x887A0005", "0X887a0005", "2289696773", "-2005270523", " 0x887A0005 "] {
+            assert_eq!(string.parse::<HResult>().unwrap(), expected, "{}", string);
+        }
+        for string in &["", "0x", "zz", "0x1ffffffff", "4294967296"] {
+            assert!(string.parse::<HResult>().is_err(), "{}", string);
+        }
+    }
+
+    #[test]
+    fn looks_up_names_and_descriptions() {
+        let info = lookup_error(0x887E0001u32 as i32).unwrap();
+        assert_eq!(info.name, "D3D12_ERROR_ADAPTER_NOT_FOUND");
+        assert_eq!(info.description,
+                   "The specified cached PSO was created on a different adapter and cannot be \
+                    reused on the current adapter.");
+        assert_eq!(info.hresult(), HResult(0x887E0001u32 as i32));
+    }
+
+    #[test]
+    fn dxgi_status_values_are_successes() {
+        let hr = HResult(0x087A0001);
+        assert_eq!(hr.name(), Some("DXGI_STATUS_OCCLUDED"));
+        assert!(hr.succeeded());
+        assert_eq!(hr.facility(), Facility::Dxgi);
+        assert_eq!(dxgi_error_msg(0x087A0001), Some("DXGI_STATUS_OCCLUDED"));
+    }
+
+    #[test]
+    fn unknown_values_have_no_name() {
+        assert!(lookup_error(0x887A00FFu32 as i32).is_none());
+        assert_eq!(HResult(0x887A00FFu32 as i32).name(), None);
+        assert_eq!(HResult(0x887A00FFu32 as i32).description(), None);
+        assert_eq!(dxgi_error_msg(0x887A00FFu32 as i32), Some("Unknown DXGI_ERROR"));
+        assert_eq!(dxgi_error_msg(0x087A00FF), Some("Unknown DXGI_STATUS"));
+        assert_eq!(dxgi_error_msg(0x80070005u32 as i32), None);
+    }
+
+    #[test]
+    fn table_values_are_unique() {
+        for (i, info) in ERROR_TABLE.iter().enumerate() {
+            assert!(ERROR_TABLE[i + 1..].iter().all(|other| other.value != info.value),
+                    "{} is in the table twice", info.name);
+        }
+    }
+
+    #[test]
+    fn context_reads_outermost_first() {
+        let err = WindowsError::from(0x80070057u32 as i32)
+            .context("creating swapchain")
+            .context("creating renderer");
+        assert_eq!(err.context_chain(), ["creating swapchain", "creating renderer"]);
+        assert_eq!(err.to_string(),
+                   "creating renderer: creating swapchain: \
+                    E_INVALIDARG (facility WIN32 0x7, code 0x57)");
+    }
+
+    #[test]
+    fn displays_the_call_site_last() {
+        let site = CallSite { file: "src/renderer.rs", line: 420, expr: "CreateSwapChain()" };
+        let err = WindowsError::from_call_site(0x80070057u32 as i32, site)
+            .context("creating swapchain");
+        assert_eq!(err.to_string(),
+                   "creating swapchain: E_INVALIDARG (facility WIN32 0x7, code 0x57) \
+                    at src/renderer.rs:420");
+        assert_eq!(WindowsError::not_implemented().to_string(), "Not implemented");
+    }
+
+    #[test]
+    fn parses_theme_files() {
+        use termcolor::Color;
+
+        let catalog = parse_color_spec_catalog("\
+            # A comment on its own line
+            windows_msg = fg:yellow bold   # and after a spec
+
+            hresult     = 208
+            note        = none
+        ").unwrap();
+        assert_eq!(catalog.windows_msg.fg(), Some(&Color::Yellow));
+        assert!(catalog.windows_msg.bold());
+        assert_eq!(catalog.hresult.fg(), Some(&Color::Ansi256(208)));
+        assert_eq!(catalog.note, termcolor::ColorSpec::new());
+        // Parts that aren't mentioned keep their defaults.
+        assert_eq!(catalog.expr, get_color_spec_catalog().expr);
+    }
+
+    #[test]
+    fn theme_errors_name_the_line() {
+        let error = |text| parse_color_spec_catalog(text).err().unwrap();
+
+        assert_eq!(error("note = none\nbogus = red"), (2, "Unknown part \"bogus\"".to_string()));
+        assert_eq!(error("# comment\n\nnote red"),
+                   (3, "Expected `part = spec`, found \"note red\"".to_string()));
+        assert_eq!(error("note = fg:notacolor").0, 1);
+
+        let err = ThemeError {
+            path:    PathBuf::from("theme.txt"),
+            line:    Some(2),
+            message: "Unknown part \"bogus\"".to_string(),
+        };
+        assert_eq!(err.to_string(), "theme.txt:2: Unknown part \"bogus\"");
+    }
+
+    #[test]
+    fn no_color_turns_off_auto_color() {
+        use termcolor::ColorChoice;
+
+        env::set_var("NO_COLOR", "1");
+        assert_eq!(color_choice(ColorMode::Auto), ColorChoice::Never);
+        assert_eq!(color_choice(ColorMode::Always), ColorChoice::Always);
+        env::remove_var("NO_COLOR");
+        assert_eq!(color_choice(ColorMode::Never), ColorChoice::Never);
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(json_string("src\\renderer.rs"), "\"src\\\\renderer.rs\"");
+        assert_eq!(json_string("a\nb\r\tc"), "\"a\\nb\\r\\tc\"");
+        assert_eq!(json_string("\u{1}\u{1f}"), "\"\\u0001\\u001f\"");
+    }
+
+    #[test]
+    fn writes_json_fields_in_order() {
+        let site = CallSite { file: "src\\renderer.rs", line: 420, expr: "Present(\"x\")" };
+        let err = WindowsError::from_call_site(0x887A0005u32 as i32, site)
+            .context("presenting")
+            .context("drawing a frame");
+        let mut json = vec![];
+        write_error_json(&mut json, &err).unwrap();
+        let json = String::from_utf8(json).unwrap();
+
+        assert!(json.starts_with("{\"kind\":\"hresult\",\"category\":\"device_removed\",\
+                                  \"win32_code\":null,\"file\":\"src\\\\renderer.rs\",\
+                                  \"line\":420,\"expression\":\"Present(\\\"x\\\")\",\
+                                  \"hresult\":\"0x887a0005\",\"severity\":\"failure\",\
+                                  \"facility\":\"DXGI\",\"facility_code\":2170,\"code\":5,\
+                                  \"name\":\"DXGI_ERROR_DEVICE_REMOVED\",\"description\":"),
+                "{}", json);
+        assert!(json.ends_with(",\"context\":[\"presenting\",\"drawing a frame\"]}\n"), "{}", json);
+        assert_eq!(json.lines().count(), 1);
+
+        let mut json = vec![];
+        write_error_json(&mut json, &WindowsError::not_implemented()).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(),
+                   "{\"kind\":\"not_implemented\",\"category\":\"not_implemented\",\
+                    \"win32_code\":null,\"file\":null,\"line\":null,\"expression\":null,\
+                    \"hresult\":null,\"severity\":null,\
+                    \"facility\":null,\"facility_code\":null,\"code\":null,\
+                    \"name\":null,\"description\":null,\"message\":\"Not implemented\",\
+                    \"context\":[]}\n");
+    }
+
+    #[test]
+    fn converts_win32_errors_to_hresults() {
+        assert_eq!(Win32Error(0).to_hresult(), HResult(0));
+        assert_eq!(Win32Error(1410).to_hresult(), HResult(0x80070582u32 as i32));
+        // Already an HRESULT.
+        assert_eq!(Win32Error(0x887A0005).to_hresult(), HResult(0x887A0005u32 as i32));
+    }
+
+    #[test]
+    fn win32_failures_without_a_last_error_are_their_own_kind() {
+        let site = || CallSite { file: "src/win32_window.rs", line: 1, expr: "RegisterClassA(&wc)" };
+
+        let err = WindowsError::from_win32_call_site(Win32Error(0), site());
+        assert_eq!(err.kind(), ErrorKind::NoLastError);
+        assert_eq!(err.hresult(), None);
+        assert_eq!(err.category(), Category::Other);
+
+        let err = WindowsError::from_win32_call_site(Win32Error(1410), site());
+        assert_eq!(err.kind(), ErrorKind::Win32(Win32Error(1410)));
+    }
+
+    #[test]
+    fn exit_codes_are_stable() {
+        let codes: Vec<_> = [Category::Other,
+                             Category::InvalidConfig,
+                             Category::NoAdapter,
+                             Category::DeviceCreation,
+                             Category::DeviceRemoved,
+                             Category::NotImplemented]
+            .iter()
+            .map(|category| category.exit_code())
+            .collect();
+        assert_eq!(codes, [1, 2, 3, 4, 5, 6]);
+        // The Rust runtime exits with 101 after a panic, which no category may reuse.
+        assert!(!codes.contains(&101));
+    }
+}
@@ -0,0 +1,354 @@
+
+//! Falling back when we can't create the device we asked for
+//!
+//! Weak laptops and VMs often can't give us the adapter or feature level
+//! we'd like. Rather than giving up, we plan a list of `Attempt`s, from the
+//! adapter `--adapter` picked down to WARP, and take the first that works.
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+use adapter::AdapterDesc;
+use config::{Config, Dx12FeatureLevel};
+use error::*;
+
+/// Which steps to take after the adapter and feature level we asked for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FallbackPolicy {
+    /// Try the other hardware adapters.
+    pub adapters: bool,
+    /// Try feature levels below the minimum, down to 11_0.
+    pub levels:   bool,
+    /// Try WARP last.
+    pub warp:     bool,
+}
+
+impl FallbackPolicy {
+    pub fn none() -> FallbackPolicy {
+        FallbackPolicy { adapters: false, levels: false, warp: false }
+    }
+}
+
+impl Default for FallbackPolicy {
+    fn default() -> FallbackPolicy {
+        FallbackPolicy { adapters: true, levels: true, warp: true }
+    }
+}
+
+impl fmt::Display for FallbackPolicy {
+    /// The steps we take, like `adapters,warp`, or `none`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let steps: Vec<&str> = [("adapters", self.adapters),
+                                ("levels",   self.levels),
+                                ("warp",     self.warp)]
+            .iter()
+            .filter(|&&(_, enabled)| enabled)
+            .map(|&(step, _)| step)
+            .collect();
+        if steps.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", steps.join(","))
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidFallbackPolicy;
+
+impl FromStr for FallbackPolicy {
+    type Err = InvalidFallbackPolicy;
+
+    /// Steps separated by `,`, in any order, or `none`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("none") {
+            return Ok(FallbackPolicy::none());
+        }
+        let mut policy = FallbackPolicy::none();
+        for step in s.split(',').map(|step| step.trim().to_lowercase()) {
+            match step.as_str() {
+                "adapters" => policy.adapters = true,
+                "levels"   => policy.levels   = true,
+                "warp"     => policy.warp     = true,
+                _          => return Err(InvalidFallbackPolicy),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Which adapter an `Attempt` uses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AdapterChoice {
+    /// An index into the adapters we planned with.
+    Index(usize),
+    Warp,
+}
+
+/// One adapter and feature level to try creating a device with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub adapter:       AdapterChoice,
+    /// For logging.
+    pub description:   String,
+    pub feature_level: Dx12FeatureLevel,
+}
+
+impl fmt::Display for Attempt {
+    /// e.g. `adapter 0 (NVIDIA GeForce GTX 1080) at 12_0` or `WARP at 11_0`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.adapter {
+            AdapterChoice::Index(i) => write!(f, "adapter {} ({}) at {}",
+                                              i, self.description, self.feature_level),
+            AdapterChoice::Warp     => write!(f, "WARP at {}", self.feature_level),
+        }
+    }
+}
+
+/// Everything we'd try for `config`, in order:
+///
+/// 1. The adapter `--adapter` selects, at the minimum feature level
+/// 2. Every other hardware adapter, at that level
+/// 3. Both again at each lower level, down to 11_0
+/// 4. WARP, at each of those levels
+///
+/// `config.fallback` turns off steps 2 to 4, and `--force-warp` skips
+/// straight to 4. If `--adapter` selects nothing, we say so on stderr before
+/// falling back.
+pub fn plan(config: &Config, adapters: &[AdapterDesc]) -> Vec<Attempt> {
+    let policy = config.fallback;
+
+    let first_level = config.feature_level.device_min();
+    let mut levels = vec![first_level];
+    // A compute-only device is a different kind of device, so we never fall
+    // back from or to 1_0_CORE.
+    if policy.levels && first_level != Dx12FeatureLevel::E1_0Core {
+        levels.extend(Dx12FeatureLevel::all()
+            .iter()
+            .rev()
+            .cloned()
+            .filter(|&level| level < first_level && level >= Dx12FeatureLevel::E11_0));
+    }
+
+    let mut order = vec![];
+    if !config.force_warp {
+        let preferred = config.adapter.select(adapters);
+        if let Some(warning) = unmatched_adapter(config, adapters) {
+            eprintln!("{}", warning);
+        }
+        order.extend(preferred);
+        if policy.adapters {
+            order.extend((0..adapters.len()).filter(|&i| {
+                Some(i) != preferred && !adapters[i].software
+            }));
+        }
+    }
+
+    let mut attempts = vec![];
+    for &level in &levels {
+        for &i in &order {
+            attempts.push(Attempt {
+                adapter:       AdapterChoice::Index(i),
+                description:   adapters[i].description.clone(),
+                feature_level: level,
+            });
+        }
+    }
+    if config.force_warp || policy.warp {
+        for &level in &levels {
+            attempts.push(Attempt {
+                adapter:       AdapterChoice::Warp,
+                description:   "WARP".to_string(),
+                feature_level: level,
+            });
+        }
+    }
+    attempts
+}
+
+/// A warning for when the adapter `config` asks for isn't in `adapters`.
+fn unmatched_adapter(config: &Config, adapters: &[AdapterDesc]) -> Option<String> {
+    if config.force_warp || config.adapter.select(adapters).is_some() {
+        return None;
+    }
+    Some(format!("No adapter matches \"{}\" (from {}), so trying the fallbacks",
+                 config.adapter,
+                 config.source("adapter")))
+}
+
+/// Try each of `attempts` with `create` until one works, logging each
+/// failure to stderr. Returns what `create` made, and which attempt made it.
+///
+/// If they all fail, the error is the first one's, since that's the one we
+/// actually wanted.
+pub fn create_first<D, F>(attempts: &[Attempt], mut create: F) -> WindowsResult<(D, Attempt)>
+    where F: FnMut(&Attempt) -> WindowsResult<D>
+{
+    let mut first_err = None;
+    for (i, attempt) in attempts.iter().enumerate() {
+        match create(attempt) {
+            Ok(device) => {
+                if i != 0 {
+                    eprintln!("Created a device on {} after {} failed attempts", attempt, i);
+                }
+                return Ok((device, attempt.clone()));
+            },
+            Err(err) => {
+                eprintln!("Couldn't create a device on {}: {}", attempt, err.kind());
+                first_err = first_err.or(Some(err));
+            },
+        }
+    }
+    match first_err {
+        Some(err) => Err(err.context(format!("trying {} adapter and feature level combinations",
+                                             attempts.len()))),
+        None      => Err(WindowsError::new(ErrorKind::NoAdapter)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use adapter::fixtures::laptop;
+    use config::Dx12FeatureLevel::*;
+
+    /// `plan()` for `config`, as `Display` writes each attempt.
+    fn plan_for(config: &Config) -> Vec<String> {
+        plan(config, &laptop()).iter().map(|attempt| attempt.to_string()).collect()
+    }
+
+    fn config(feature_level: &str, adapter: &str, fallback: &str) -> Config {
+        Config {
+            feature_level: feature_level.parse().unwrap(),
+            adapter:       adapter.parse().unwrap(),
+            fallback:      fallback.parse().unwrap(),
+            ..Config::default()
+        }
+    }
+
+    fn attempt(adapter: AdapterChoice, feature_level: Dx12FeatureLevel) -> Attempt {
+        Attempt { adapter, description: format!("{:?}", adapter), feature_level }
+    }
+
+    #[test]
+    fn plans_the_preferred_adapter_then_the_others_then_warp() {
+        let config = config("11_0..12_2", "nvidia", "adapters,levels,warp");
+        assert_eq!(plan_for(&config), vec![
+            "adapter 1 (NVIDIA GeForce GTX 1080) at 11_0",
+            "adapter 0 (Intel(R) UHD Graphics 630) at 11_0",
+            "WARP at 11_0",
+        ]);
+    }
+
+    #[test]
+    fn plans_every_adapter_at_each_lower_level_before_warp() {
+        let config = config("12_0..12_2", "0", "adapters,levels,warp");
+        assert_eq!(plan_for(&config), vec![
+            "adapter 0 (Intel(R) UHD Graphics 630) at 12_0",
+            "adapter 1 (NVIDIA GeForce GTX 1080) at 12_0",
+            "adapter 0 (Intel(R) UHD Graphics 630) at 11_1",
+            "adapter 1 (NVIDIA GeForce GTX 1080) at 11_1",
+            "adapter 0 (Intel(R) UHD Graphics 630) at 11_0",
+            "adapter 1 (NVIDIA GeForce GTX 1080) at 11_0",
+            "WARP at 12_0",
+            "WARP at 11_1",
+            "WARP at 11_0",
+        ]);
+    }
+
+    #[test]
+    fn force_warp_skips_the_adapters() {
+        let mut config = config("11_1", "nvidia", "adapters,levels,warp");
+        config.force_warp = true;
+        assert_eq!(plan_for(&config), vec!["WARP at 11_1", "WARP at 11_0"]);
+
+        // Even when the policy says not to fall back to WARP.
+        config.fallback = FallbackPolicy::none();
+        assert_eq!(plan_for(&config), vec!["WARP at 11_1"]);
+    }
+
+    #[test]
+    fn an_empty_policy_only_tries_what_we_asked_for() {
+        let config = config("12_0", "1", "none");
+        assert_eq!(plan_for(&config), vec!["adapter 1 (NVIDIA GeForce GTX 1080) at 12_0"]);
+    }
+
+    #[test]
+    fn a_missing_preferred_adapter_warns_then_falls_back() {
+        let mut config = config("11_0", "0", "adapters,warp");
+        assert_eq!(unmatched_adapter(&config, &laptop()), None);
+
+        config.apply_toml("adapter = \"amd\"\n", Path::new("dx12.toml")).unwrap();
+        assert_eq!(unmatched_adapter(&config, &laptop()).unwrap(),
+                   "No adapter matches \"amd\" (from dx12.toml), so trying the fallbacks");
+        assert_eq!(plan_for(&config), vec![
+            "adapter 0 (Intel(R) UHD Graphics 630) at 11_0",
+            "adapter 1 (NVIDIA GeForce GTX 1080) at 11_0",
+            "WARP at 11_0",
+        ]);
+
+        let config = Config { fallback: FallbackPolicy::none(), ..config };
+        assert!(plan_for(&config).is_empty());
+    }
+
+    #[test]
+    fn never_falls_back_from_1_0_core() {
+        let config = config("1_0_core", "0", "adapters,levels,warp");
+        assert_eq!(plan_for(&config), vec![
+            "adapter 0 (Intel(R) UHD Graphics 630) at 1_0_core",
+            "adapter 1 (NVIDIA GeForce GTX 1080) at 1_0_core",
+            "WARP at 1_0_core",
+        ]);
+    }
+
+    #[test]
+    fn create_first_takes_the_first_that_works() {
+        let attempts = vec![attempt(AdapterChoice::Index(0), E12_0),
+                            attempt(AdapterChoice::Index(1), E12_0),
+                            attempt(AdapterChoice::Warp,     E12_0)];
+        let mut tried = vec![];
+        let (device, worked) = create_first(&attempts, |attempt| {
+                tried.push(attempt.adapter);
+                match attempt.adapter {
+                    AdapterChoice::Index(0) => Err(WindowsError::from(0x887A0004u32 as i32)),
+                    _                       => Ok("device"),
+                }
+            })
+            .unwrap();
+        assert_eq!(device, "device");
+        assert_eq!(worked, attempts[1]);
+        assert_eq!(tried, vec![AdapterChoice::Index(0), AdapterChoice::Index(1)]);
+    }
+
+    #[test]
+    fn create_first_returns_the_first_error() {
+        let attempts = vec![attempt(AdapterChoice::Index(0), E12_0),
+                            attempt(AdapterChoice::Warp,     E12_0)];
+        let err = create_first::<(), _>(&attempts, |attempt| {
+                match attempt.adapter {
+                    AdapterChoice::Index(_) => Err(WindowsError::from(0x887A0004u32 as i32)
+                                                       .context("creating on hardware")),
+                    AdapterChoice::Warp     => Err(WindowsError::from(0x80004002u32 as i32)
+                                                       .context("creating on WARP")),
+                }
+            })
+            .unwrap_err();
+        assert_eq!(err.hresult(), Some(HResult(0x887A0004u32 as i32)));
+        assert_eq!(err.context_chain(), &[
+            "creating on hardware".to_string(),
+            "trying 2 adapter and feature level combinations".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn create_first_without_attempts_has_no_adapter() {
+        let err = create_first::<(), _>(&[], |_| panic!("there's nothing to try")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NoAdapter);
+        assert_eq!(err.category(), Category::NoAdapter);
+    }
+}
@@ -0,0 +1,456 @@
+
+//! Debug layer messages, through `ID3D12InfoQueue`
+//!
+//! The debug layer only writes its messages to the debugger, which we never
+//! see from a terminal. Instead, we drain them from the device's info queue and
+//! print the ones our `MessageFilter` lets through.
+//!
+//! Filters come from `--debug-filter` and config files as rules, like
+//! `deny:id=820,1008`, and `rules()` turns them back into text for
+//! `config --dump`.
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+/// A `D3D12_MESSAGE_SEVERITY`. The most severe come first.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageSeverity {
+    Corruption,
+    Error,
+    Warning,
+    Info,
+    Message,
+}
+
+impl MessageSeverity {
+    pub fn all() -> &'static [MessageSeverity] {
+        &[
+            MessageSeverity::Corruption,
+            MessageSeverity::Error,
+            MessageSeverity::Warning,
+            MessageSeverity::Info,
+            MessageSeverity::Message,
+        ]
+    }
+
+    pub fn from_u32(value: u32) -> Option<MessageSeverity> {
+        MessageSeverity::all().get(value as usize).cloned()
+    }
+
+    pub fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageSeverity::Corruption => "corruption",
+            MessageSeverity::Error      => "error",
+            MessageSeverity::Warning    => "warning",
+            MessageSeverity::Info       => "info",
+            MessageSeverity::Message    => "message",
+        }
+    }
+}
+
+impl fmt::Display for MessageSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidMessageSeverity;
+
+impl FromStr for MessageSeverity {
+    type Err = InvalidMessageSeverity;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MessageSeverity::all()
+            .iter()
+            .find(|severity| severity.name().eq_ignore_ascii_case(s))
+            .cloned()
+            .ok_or(InvalidMessageSeverity)
+    }
+}
+
+/// A `D3D12_MESSAGE_CATEGORY`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageCategory {
+    ApplicationDefined,
+    Miscellaneous,
+    Initialization,
+    Cleanup,
+    Compilation,
+    StateCreation,
+    StateSetting,
+    StateGetting,
+    ResourceManipulation,
+    Execution,
+    Shader,
+}
+
+impl MessageCategory {
+    pub fn all() -> &'static [MessageCategory] {
+        &[
+            MessageCategory::ApplicationDefined,
+            MessageCategory::Miscellaneous,
+            MessageCategory::Initialization,
+            MessageCategory::Cleanup,
+            MessageCategory::Compilation,
+            MessageCategory::StateCreation,
+            MessageCategory::StateSetting,
+            MessageCategory::StateGetting,
+            MessageCategory::ResourceManipulation,
+            MessageCategory::Execution,
+            MessageCategory::Shader,
+        ]
+    }
+
+    pub fn from_u32(value: u32) -> Option<MessageCategory> {
+        MessageCategory::all().get(value as usize).cloned()
+    }
+
+    pub fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageCategory::ApplicationDefined   => "application_defined",
+            MessageCategory::Miscellaneous        => "miscellaneous",
+            MessageCategory::Initialization       => "initialization",
+            MessageCategory::Cleanup              => "cleanup",
+            MessageCategory::Compilation          => "compilation",
+            MessageCategory::StateCreation        => "state_creation",
+            MessageCategory::StateSetting         => "state_setting",
+            MessageCategory::StateGetting         => "state_getting",
+            MessageCategory::ResourceManipulation => "resource_manipulation",
+            MessageCategory::Execution            => "execution",
+            MessageCategory::Shader               => "shader",
+        }
+    }
+}
+
+impl fmt::Display for MessageCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidMessageCategory;
+
+impl FromStr for MessageCategory {
+    type Err = InvalidMessageCategory;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MessageCategory::all()
+            .iter()
+            .find(|category| category.name().eq_ignore_ascii_case(s))
+            .cloned()
+            .ok_or(InvalidMessageCategory)
+    }
+}
+
+/// One message from the debug layer.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub severity:    MessageSeverity,
+    pub category:    MessageCategory,
+    /// A `D3D12_MESSAGE_ID`.
+    pub id:          u32,
+    pub description: String,
+}
+
+impl fmt::Display for Message {
+    /// Like the debugger output,
+    /// e.g. `D3D12 ERROR: ... [ STATE_CREATION ERROR #599 ]`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = self.severity.name().to_uppercase();
+        write!(f, "D3D12 {}: {} [ {} {} #{} ]",
+               severity,
+               self.description.trim_matches('\0'),
+               self.category.name().to_uppercase(),
+               severity,
+               self.id)
+    }
+}
+
+/// Which debug layer messages we print.
+///
+/// A message is dropped if it matches anything in a deny list. Otherwise,
+/// every allow list that isn't empty has to include it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageFilter {
+    pub allow_severities: Vec<MessageSeverity>,
+    pub allow_categories: Vec<MessageCategory>,
+    pub allow_ids:        Vec<u32>,
+    pub deny_severities:  Vec<MessageSeverity>,
+    pub deny_categories:  Vec<MessageCategory>,
+    pub deny_ids:         Vec<u32>,
+}
+
+impl MessageFilter {
+    pub fn allows(&self, message: &Message) -> bool {
+        let denied = self.deny_severities.contains(&message.severity) ||
+                     self.deny_categories.contains(&message.category) ||
+                     self.deny_ids.contains(&message.id);
+        if denied {
+            return false;
+        }
+
+        fn allowed_by<T: PartialEq>(list: &[T], value: &T) -> bool {
+            list.is_empty() || list.contains(value)
+        }
+        allowed_by(&self.allow_severities, &message.severity) &&
+        allowed_by(&self.allow_categories, &message.category) &&
+        allowed_by(&self.allow_ids,        &message.id)
+    }
+
+    /// The rules that would build this filter with `add_rule()`.
+    pub fn rules(&self) -> Vec<String> {
+        fn rule<T: ToString>(rules: &mut Vec<String>, key: &str, values: &[T]) {
+            if !values.is_empty() {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                rules.push(format!("{}={}", key, values.join(",")));
+            }
+        }
+        let mut rules = vec![];
+        rule(&mut rules, "allow:severity", &self.allow_severities);
+        rule(&mut rules, "allow:category", &self.allow_categories);
+        rule(&mut rules, "allow:id",       &self.allow_ids);
+        rule(&mut rules, "deny:severity",  &self.deny_severities);
+        rule(&mut rules, "deny:category",  &self.deny_categories);
+        rule(&mut rules, "deny:id",        &self.deny_ids);
+        rules
+    }
+
+    /// Add a rule like `deny:id=820,1008` or `allow:severity=error,warning`.
+    pub fn add_rule(&mut self, rule: &str) -> Result<(), InvalidMessageFilter> {
+        let mut halves = rule.splitn(2, '=');
+        let (key, values) = match (halves.next(), halves.next()) {
+            (Some(key), Some(values)) => (key.trim(), values),
+            _ => return Err(InvalidMessageFilter),
+        };
+        let values = values.split(',')
+                           .map(|value| value.trim())
+                           .filter(|value| !value.is_empty());
+
+        match key {
+            "allow:severity" => extend_parsed(&mut self.allow_severities, values),
+            "deny:severity"  => extend_parsed(&mut self.deny_severities,  values),
+            "allow:category" => extend_parsed(&mut self.allow_categories, values),
+            "deny:category"  => extend_parsed(&mut self.deny_categories,  values),
+            "allow:id"       => extend_parsed(&mut self.allow_ids,        values),
+            "deny:id"        => extend_parsed(&mut self.deny_ids,         values),
+            _                => Err(InvalidMessageFilter),
+        }
+    }
+}
+
+fn extend_parsed<'a, T, I>(list: &mut Vec<T>, values: I) -> Result<(), InvalidMessageFilter>
+    where T: FromStr,
+          I: Iterator<Item=&'a str>
+{
+    for value in values {
+        list.push(value.parse().map_err(|_| InvalidMessageFilter)?);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidMessageFilter;
+
+impl FromStr for MessageFilter {
+    type Err = InvalidMessageFilter;
+
+    /// Rules separated by `;`, e.g. `deny:id=820,1008; allow:severity=error`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = MessageFilter::default();
+        for rule in s.split(';').map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+            filter.add_rule(rule)?;
+        }
+        Ok(filter)
+    }
+}
+
+#[cfg(windows)]
+pub use self::windows::InfoQueue;
+
+#[cfg(windows)]
+mod windows {
+    use std::{
+        ffi::CStr,
+        ptr,
+    };
+
+    use wio::com::ComPtr;
+
+    use winapi::um::{
+        d3d12::ID3D12Device,
+        d3d12sdklayers::*,
+    };
+
+    use error::*;
+    use super::*;
+
+    /// A device's info queue, and what we want out of it.
+    pub struct InfoQueue {
+        queue:  ComPtr<ID3D12InfoQueue>,
+        filter: MessageFilter,
+    }
+
+    impl InfoQueue {
+        /// Only works when the device was created with the debug layer on.
+        ///
+        /// With `break_on`, we break into the debugger on any message at
+        /// least that severe.
+        pub fn new(device:   &ComPtr<ID3D12Device>,
+                   filter:   MessageFilter,
+                   break_on: Option<MessageSeverity>) -> WindowsResult<InfoQueue> {
+            let queue: ComPtr<ID3D12InfoQueue> = device.cast()?;
+            if let Some(break_on) = break_on {
+                for &severity in MessageSeverity::all() {
+                    if severity <= break_on {
+                        hr!(unsafe { queue.SetBreakOnSeverity(severity.to_u32(), 1) })?;
+                    }
+                }
+            }
+            Ok(InfoQueue { queue, filter })
+        }
+
+        /// Take every stored message off of the queue, and return the ones
+        /// our filter allows.
+        pub fn drain(&self) -> WindowsResult<Vec<Message>> {
+            let mut messages = vec![];
+            unsafe {
+                let count = self.queue.GetNumStoredMessages();
+                for i in 0..count {
+                    let mut len = 0;
+                    hr!(self.queue.GetMessage(i, ptr::null_mut(), &mut len))?;
+
+                    // D3D12_MESSAGE is followed by its description, so we
+                    // need a buffer that's big enough and aligned for both.
+                    let mut buffer: Vec<u64> = vec![0; (len + 7) / 8];
+                    let message = buffer.as_mut_ptr() as *mut D3D12_MESSAGE;
+                    hr!(self.queue.GetMessage(i, message, &mut len))?;
+                    let message = &*message;
+
+                    let description = if message.pDescription.is_null() {
+                        String::new()
+                    } else {
+                        CStr::from_ptr(message.pDescription).to_string_lossy().into_owned()
+                    };
+                    let message = Message {
+                        severity: MessageSeverity::from_u32(message.Severity)
+                                      .unwrap_or(MessageSeverity::Message),
+                        category: MessageCategory::from_u32(message.Category)
+                                      .unwrap_or(MessageCategory::Miscellaneous),
+                        id:       message.ID,
+                        description,
+                    };
+                    if self.filter.allows(&message) {
+                        messages.push(message);
+                    }
+                }
+                self.queue.ClearStoredMessages();
+            }
+            Ok(messages)
+        }
+
+        /// Print everything `drain()` returns to stderr.
+        pub fn print(&self) {
+            match self.drain() {
+                Ok(messages) => {
+                    for message in messages {
+                        eprintln!("{}", message);
+                    }
+                },
+                Err(err) => print_error(&err.context("reading debug layer messages")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(severity: MessageSeverity, category: MessageCategory, id: u32) -> Message {
+        Message { severity, category, id, description: String::new() }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let filter: MessageFilter = "deny:id=820, 1008; allow:severity=Error,warning;\
+                                     allow:category=state_creation"
+            .parse()
+            .unwrap();
+        assert_eq!(filter, MessageFilter {
+            allow_severities: vec![MessageSeverity::Error, MessageSeverity::Warning],
+            allow_categories: vec![MessageCategory::StateCreation],
+            deny_ids:         vec![820, 1008],
+            ..MessageFilter::default()
+        });
+        assert_eq!("".parse::<MessageFilter>(), Ok(MessageFilter::default()));
+        assert_eq!(" ; ;".parse::<MessageFilter>(), Ok(MessageFilter::default()));
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for rule in &["deny:id",                // No `=`
+                      "deny=820",               // No kind
+                      "block:id=820",           // Bad key
+                      "allow:colour=red",       // Bad key
+                      "deny:id=820,eight",      // Not an id
+                      "allow:severity=fatal",   // Not a severity
+                      "deny:category=physics"]  // Not a category
+        {
+            assert_eq!(rule.parse::<MessageFilter>(), Err(InvalidMessageFilter), "{}", rule);
+            assert_eq!(MessageFilter::default().add_rule(rule), Err(InvalidMessageFilter), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn deny_beats_allow() {
+        let filter: MessageFilter = "allow:severity=error; deny:id=820".parse().unwrap();
+        let error = |id| message(MessageSeverity::Error, MessageCategory::Execution, id);
+
+        assert!(filter.allows(&error(1)));
+        assert!(!filter.allows(&error(820)));
+        assert!(!filter.allows(&message(MessageSeverity::Warning, MessageCategory::Execution, 1)));
+
+        let filter: MessageFilter = "allow:id=820; deny:category=execution".parse().unwrap();
+        assert!(!filter.allows(&error(820)));
+    }
+
+    #[test]
+    fn empty_filter_allows_everything() {
+        let filter = MessageFilter::default();
+        for &severity in MessageSeverity::all() {
+            for &category in MessageCategory::all() {
+                assert!(filter.allows(&message(severity, category, 0)));
+            }
+        }
+    }
+
+    #[test]
+    fn rules_round_trip() {
+        let filter: MessageFilter = "allow:severity=corruption,error; allow:category=shader; \
+                                     allow:id=1; deny:severity=info; \
+                                     deny:category=cleanup,execution; deny:id=820,1008"
+            .parse()
+            .unwrap();
+        let rules = filter.rules();
+        assert_eq!(rules, vec!["allow:severity=corruption,error",
+                               "allow:category=shader",
+                               "allow:id=1",
+                               "deny:severity=info",
+                               "deny:category=cleanup,execution",
+                               "deny:id=820,1008"]);
+        assert_eq!(rules.join(";").parse::<MessageFilter>(), Ok(filter));
+        assert!(MessageFilter::default().rules().is_empty());
+    }
+}
@@ -0,0 +1,409 @@
+
+//! Reporting leaked D3D12 and DXGI objects
+//!
+//! `IDXGIDebug::ReportLiveObjects` only writes free-form text to the debugger.
+//! We catch that text in the DXGI info queue instead, and parse it back into a
+//! `LiveObjectReport` we can print, check, or compare with another run's.
+
+use std::{
+    cmp,
+    io::{self, Write},
+};
+
+/// One object that was still alive, as the debug layer describes it:
+///
+/// ```text
+/// Live ID3D12Resource at 0x000001F7C0A8E0A0, Name: Vertex Buffer, Refcount: 1, IntRef: 0
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveObject {
+    pub type_name: String,
+    pub address:   u64,
+    pub name:      Option<String>,
+    pub refcount:  u32,
+    /// References the runtime holds itself. DXGI objects don't report this.
+    pub intref:    Option<u32>,
+}
+
+impl LiveObject {
+    /// Parse one line of `ReportLiveObjects` output. Summary lines and
+    /// anything else we don't recognize give `None`.
+    pub fn parse(line: &str) -> Option<LiveObject> {
+        // Messages that go through the info queue can still have the
+        // "D3D12 WARNING: " prefix and " [ ... ]" suffix of debugger output.
+        let start = line.find("Live ")?;
+        let line = &line[start + "Live ".len()..];
+        let line = match line.find(" [ ") {
+            Some(end) => &line[..end],
+            None      => line,
+        };
+
+        let at = line.find(" at ")?;
+        let type_name = line[..at].trim();
+        let rest = &line[at + " at ".len()..];
+        let (address, rest) = match rest.find(',') {
+            Some(comma) => (&rest[..comma], &rest[comma + 1..]),
+            None        => (rest, ""),
+        };
+        let address = address.trim();
+        if !address.starts_with("0x") && !address.starts_with("0X") {
+            return None;
+        }
+        let address = u64::from_str_radix(&address[2..], 16).ok()?;
+
+        // Names can have commas in them, so we find the fields we know, and
+        // a name is everything between "Name: " and the next one of those.
+        let refcount = field(rest, "Refcount: ")?;
+        let intref = field(rest, "IntRef: ");
+        let name = rest.find("Name: ").map(|start| {
+            let name = &rest[start + "Name: ".len()..];
+            let end = [", Refcount: ", ", IntRef: "]
+                .iter()
+                .filter_map(|next| name.find(next))
+                .min()
+                .unwrap_or(name.len());
+            name[..end].to_string()
+        });
+
+        Some(LiveObject {
+            type_name: type_name.to_string(),
+            address,
+            name,
+            refcount,
+            intref,
+        })
+    }
+
+    /// Objects from different runs won't share addresses, so we match them
+    /// up by what they are and what they're called.
+    fn same_kind(&self, other: &LiveObject) -> bool {
+        self.type_name == other.type_name && self.name == other.name
+    }
+}
+
+/// The number right after `key` in `text`.
+fn field(text: &str, key: &str) -> Option<u32> {
+    let start = text.find(key)? + key.len();
+    let digits: String = text[start..].chars().take_while(|c| c.is_digit(10)).collect();
+    digits.parse().ok()
+}
+
+/// Everything that was still alive when we asked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveObjectReport {
+    pub objects: Vec<LiveObject>,
+}
+
+impl LiveObjectReport {
+    /// Build a report from `ReportLiveObjects` output, skipping lines that
+    /// aren't about a single object.
+    pub fn parse<'a, I: IntoIterator<Item=&'a str>>(lines: I) -> LiveObjectReport {
+        LiveObjectReport {
+            objects: lines.into_iter().filter_map(LiveObject::parse).collect(),
+        }
+    }
+
+    /// Whether nothing we made outlived us.
+    pub fn is_clean(&self) -> bool {
+        self.objects.iter().all(|object| object.refcount == 0)
+    }
+
+    /// Objects in `self` that weren't in `earlier`, and the other way around.
+    pub fn diff(&self, earlier: &LiveObjectReport) -> LiveObjectDiff {
+        let mut unmatched: Vec<&LiveObject> = earlier.objects.iter().collect();
+        let mut diff = LiveObjectDiff::default();
+        for object in &self.objects {
+            match unmatched.iter().position(|other| object.same_kind(other)) {
+                Some(i) => { unmatched.remove(i); },
+                None    => diff.added.push(object.clone()),
+            }
+        }
+        diff.removed = unmatched.into_iter().cloned().collect();
+        diff
+    }
+
+    /// Write one row per object.
+    ///
+    /// ```text
+    /// Type            Name           Refcount  IntRef  Address
+    /// ID3D12Device    <unnamed>             2       0  0x000001f7c0a10000
+    /// ID3D12Resource  Vertex Buffer         1       0  0x000001f7c0a8e0a0
+    /// ```
+    pub fn write_table<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let names: Vec<&str> = self.objects
+            .iter()
+            .map(|object| object.name.as_ref().map(|s| s.as_str()).unwrap_or("<unnamed>"))
+            .collect();
+        let type_width = self.objects.iter()
+            .map(|object| object.type_name.len())
+            .fold("Type".len(), cmp::max);
+        let name_width = names.iter()
+            .map(|name| name.len())
+            .fold("Name".len(), cmp::max);
+
+        writeln!(w, "{:<tw$}  {:<nw$}  {:>8}  {:>6}  {}",
+                 "Type", "Name", "Refcount", "IntRef", "Address",
+                 tw = type_width,
+                 nw = name_width)?;
+        for (object, name) in self.objects.iter().zip(names) {
+            let intref = match object.intref {
+                Some(intref) => intref.to_string(),
+                None         => "-".to_string(),
+            };
+            writeln!(w, "{:<tw$}  {:<nw$}  {:>8}  {:>6}  0x{:016x}",
+                     object.type_name, name, object.refcount, intref, object.address,
+                     tw = type_width,
+                     nw = name_width)?;
+        }
+        Ok(())
+    }
+}
+
+/// What `LeakCheck` says at shutdown: nothing if `report` is clean, or else a
+/// table of what outlived us.
+pub fn write_leaks<W: Write>(w: &mut W, report: &LiveObjectReport) -> io::Result<()> {
+    if report.is_clean() {
+        return Ok(());
+    }
+    writeln!(w, "Live objects at shutdown:")?;
+    report.write_table(w)
+}
+
+/// How two `LiveObjectReport`s differ.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveObjectDiff {
+    pub added:   Vec<LiveObject>,
+    pub removed: Vec<LiveObject>,
+}
+
+impl LiveObjectDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[cfg(windows)]
+pub use self::windows::LeakCheck;
+
+#[cfg(windows)]
+mod windows {
+    use std::{
+        ffi::CStr,
+        ptr,
+    };
+
+    use wio::com::ComPtr;
+
+    use winapi::{
+        Interface,
+        shared::dxgi1_3::DXGIGetDebugInterface1,
+        um::dxgidebug::*,
+    };
+
+    use error::*;
+    use super::*;
+
+    /// Reports live objects when it's dropped, unless you `finish()` it.
+    ///
+    /// Make this before anything you want checked, so it's dropped after.
+    pub struct LeakCheck {
+        debug:      ComPtr<IDXGIDebug>,
+        info_queue: ComPtr<IDXGIInfoQueue>,
+        finished:   bool,
+    }
+
+    impl LeakCheck {
+        /// MS Docs:
+        ///      The DXGIGetDebugInterface1 function returns E_NOINTERFACE on
+        ///      systems without the Windows Software Development Kit (SDK)
+        ///      installed, because it's a development-time aid.
+        pub fn new() -> WindowsResult<LeakCheck> {
+            let debug: ComPtr<IDXGIDebug> = unsafe {
+                let mut ptr: *mut IDXGIDebug = ptr::null_mut();
+                hr!(DXGIGetDebugInterface1(0, // flags, unused
+                                           &IDXGIDebug::uuidof(),
+                                           &mut ptr as *mut _ as *mut _))
+                    .context("getting the DXGI debug interface")?;
+                ComPtr::from_raw(ptr)
+            };
+            let info_queue: ComPtr<IDXGIInfoQueue> = unsafe {
+                let mut ptr: *mut IDXGIInfoQueue = ptr::null_mut();
+                hr!(DXGIGetDebugInterface1(0, // flags, unused
+                                           &IDXGIInfoQueue::uuidof(),
+                                           &mut ptr as *mut _ as *mut _))
+                    .context("getting the DXGI info queue")?;
+                ComPtr::from_raw(ptr)
+            };
+            Ok(LeakCheck { debug, info_queue, finished: false })
+        }
+
+        /// Report what's alive now, instead of printing it when dropped.
+        /// Release everything you want checked first.
+        pub fn finish(mut self) -> WindowsResult<LiveObjectReport> {
+            self.finished = true;
+            self.report()
+        }
+
+        /// Ask for everything that's alive right now.
+        pub fn report(&self) -> WindowsResult<LiveObjectReport> {
+            let mut lines = vec![];
+            unsafe {
+                self.info_queue.ClearStoredMessages(DXGI_DEBUG_ALL);
+                hr!(self.debug.ReportLiveObjects(DXGI_DEBUG_ALL,
+                                                 DXGI_DEBUG_RLO_DETAIL |
+                                                 DXGI_DEBUG_RLO_IGNORE_INTERNAL))?;
+
+                let count = self.info_queue.GetNumStoredMessages(DXGI_DEBUG_ALL);
+                for i in 0..count {
+                    let mut len = 0;
+                    hr!(self.info_queue.GetMessage(DXGI_DEBUG_ALL, i, ptr::null_mut(), &mut len))?;
+
+                    // Like D3D12_MESSAGE, the description follows the struct.
+                    let mut buffer: Vec<u64> = vec![0; (len + 7) / 8];
+                    let message = buffer.as_mut_ptr() as *mut DXGI_INFO_QUEUE_MESSAGE;
+                    hr!(self.info_queue.GetMessage(DXGI_DEBUG_ALL, i, message, &mut len))?;
+
+                    let description = (*message).pDescription;
+                    if !description.is_null() {
+                        lines.push(CStr::from_ptr(description).to_string_lossy().into_owned());
+                    }
+                }
+                self.info_queue.ClearStoredMessages(DXGI_DEBUG_ALL);
+            }
+            Ok(LiveObjectReport::parse(lines.iter().map(|line| line.as_str())))
+        }
+    }
+
+    impl Drop for LeakCheck {
+        fn drop(&mut self) {
+            if self.finished {
+                return;
+            }
+            match self.report() {
+                Ok(report) => {
+                    let stderr = io::stderr();
+                    let _ = write_leaks(&mut stderr.lock(), &report);
+                },
+                Err(err) => print_error(&err.context("reporting live objects")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What the debug layer gave us for a renderer that forgot to release
+    // its vertex buffer, as it comes out of the info queues.
+    const REPORT: &[&str] = &[
+        "D3D12 WARNING: Live ID3D12Device at 0x000001F7C0A10000, Refcount: 2 \
+         [ STATE_CREATION WARNING #274: LIVE_DEVICE]",
+        "D3D12 WARNING: \tLive ID3D12CommandQueue at 0x000001F7C0B2A3C0, Name: Direct Queue, \
+         Refcount: 0, IntRef: 1 [ STATE_CREATION WARNING #570: LIVE_COMMANDQUEUE]",
+        "D3D12 WARNING: \tLive ID3D12Resource at 0x000001F7C0A8E0A0, Name: Vertex Buffer, \
+         Refcount: 1, IntRef: 0 [ STATE_CREATION WARNING #575: LIVE_RESOURCE]",
+        "DXGI WARNING: Live IDXGIFactory at 0x000001F7BEE6A6B0, Refcount: 1 \
+         [ STATE_CREATION WARNING #0: ]",
+        "DXGI WARNING: Live                         IDXGIFactory :      1 \
+         [ STATE_CREATION WARNING #0: ]",
+    ];
+
+    fn object(type_name: &str, address: u64, name: Option<&str>, refcount: u32, intref: Option<u32>)
+        -> LiveObject
+    {
+        LiveObject {
+            type_name: type_name.to_string(),
+            address,
+            name:      name.map(|name| name.to_string()),
+            refcount,
+            intref,
+        }
+    }
+
+    #[test]
+    fn parses_objects() {
+        let report = LiveObjectReport::parse(REPORT.iter().cloned());
+        assert_eq!(report.objects, vec![
+            object("ID3D12Device",       0x000001F7C0A10000, None,                  2, None),
+            object("ID3D12CommandQueue", 0x000001F7C0B2A3C0, Some("Direct Queue"),  0, Some(1)),
+            object("ID3D12Resource",     0x000001F7C0A8E0A0, Some("Vertex Buffer"), 1, Some(0)),
+            object("IDXGIFactory",       0x000001F7BEE6A6B0, None,                  1, None),
+        ]);
+    }
+
+    #[test]
+    fn names_can_have_commas() {
+        let line = "Live ID3D12Resource at 0x10, Name: Buffer, the big one, Refcount: 1, IntRef: 0";
+        assert_eq!(LiveObject::parse(line),
+                   Some(object("ID3D12Resource", 0x10, Some("Buffer, the big one"), 1, Some(0))));
+    }
+
+    #[test]
+    fn skips_what_isnt_an_object() {
+        for line in &[
+            "",
+            "D3D12 WARNING: ID3D12Device::ReportLiveDeviceObjects",
+            "DXGI WARNING: Live                         IDXGIFactory :      1",
+            "Live ID3D12Device at nowhere, Refcount: 1",
+            "Live ID3D12Device at 0xZZ, Refcount: 1",
+            "Live ID3D12Device at 0x10",
+        ] {
+            assert_eq!(LiveObject::parse(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn clean_when_nothing_is_referenced() {
+        assert!(LiveObjectReport::default().is_clean());
+        assert!(!LiveObjectReport::parse(REPORT.iter().cloned()).is_clean());
+
+        // Only the runtime still holds the queue.
+        let report = LiveObjectReport::parse(REPORT[1..2].iter().cloned());
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn diff_matches_by_type_and_name() {
+        let earlier = LiveObjectReport::parse(REPORT.iter().cloned());
+        // Another run: different addresses, the same device and factory, an
+        // index buffer instead of the vertex buffer, and no queue.
+        let later = LiveObjectReport {
+            objects: vec![
+                object("ID3D12Device",   0x20, None,                 2, None),
+                object("ID3D12Resource", 0x30, Some("Index Buffer"), 1, Some(0)),
+                object("IDXGIFactory",   0x40, None,                 1, None),
+            ],
+        };
+        let diff = later.diff(&earlier);
+        assert_eq!(diff.added, vec![later.objects[1].clone()]);
+        assert_eq!(diff.removed, vec![earlier.objects[1].clone(), earlier.objects[2].clone()]);
+
+        assert!(later.diff(&later).is_empty());
+    }
+
+    #[test]
+    fn diff_counts_objects_of_the_same_kind() {
+        let buffer = object("ID3D12Resource", 0x10, None, 1, Some(0));
+        let one = LiveObjectReport { objects: vec![buffer.clone()] };
+        let three = LiveObjectReport { objects: vec![buffer.clone(), buffer.clone(), buffer.clone()] };
+        assert_eq!(three.diff(&one).added.len(), 2);
+        assert_eq!(one.diff(&three).removed.len(), 2);
+    }
+
+    #[test]
+    fn writes_leaks_only_when_there_are_some() {
+        let mut text = vec![];
+        write_leaks(&mut text, &LiveObjectReport::default()).unwrap();
+        assert!(text.is_empty());
+
+        let report = LiveObjectReport::parse(REPORT[..3].iter().cloned());
+        write_leaks(&mut text, &report).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(),
+                   "Live objects at shutdown:\n\
+                    Type                Name           Refcount  IntRef  Address\n\
+                    ID3D12Device        <unnamed>             2       -  0x000001f7c0a10000\n\
+                    ID3D12CommandQueue  Direct Queue          0       1  0x000001f7c0b2a3c0\n\
+                    ID3D12Resource      Vertex Buffer         1       0  0x000001f7c0a8e0a0\n");
+    }
+}
@@ -1,54 +1,52 @@
 
+/// Turn a failed `HRESULT` into an `Err(WindowsError)` that remembers where
+/// it came from. Printing is up to whoever ends up handling it.
+///
+/// With `--trace-api`, every call is also timed and recorded in `trace`.
 macro_rules! hr {
     ($hresult:expr) => {
         {
+            let call_site = ::error::CallSite {
+                file: file!(),
+                line: line!(),
+                expr: stringify!($hresult),
+            };
+            let start = ::trace::start();
             // It's important to only evaluate this once.
             let hresult = $hresult;
+            ::trace::record(start, call_site, hresult);
             if !::winapi::shared::winerror::SUCCEEDED(hresult) {
-                use ::std::io::Write;
-                use ::termcolor::{
-                    Color,
-                    ColorChoice,
-                    ColorSpec,
-                    StandardStream,
-                    WriteColor,
-                };
-
-                let mut stderr = StandardStream::stderr(ColorChoice::Always);
-
-                let empty = ColorSpec::new();
-
-                let mut code = ColorSpec::new();
-                code.set_fg(Some(Color::Green));
-                code.set_intense(true);
-                let code = code;
-
-                let mut focus = ColorSpec::new();
-                focus.set_fg(Some(Color::Red));
-                focus.set_intense(true);
-                let focus = focus;
-
-                write!(stderr, "{}:{}: ", file!(), line!());
-
-                match ::error::win_error_msg(hresult) {
-                    ""  => { write!(stderr, "0x{:x}", hresult); },
-                    msg => {
-                        stderr.set_color(&focus).unwrap();
-                        write!(stderr, "{}", msg);
-                        stderr.set_color(&empty).unwrap();
-                        write!(stderr, " (0x{:x}) ", hresult);
-                    },
-                }
-                write!(stderr, "from:\n    ");
-
-                stderr.set_color(&code).unwrap();
-                write!(stderr, "{}\n",
-                       stringify!($hresult).replace("\n", "\n    "));
-                stderr.set_color(&empty).unwrap();
-                Err(hresult)
+                let err = ::error::WindowsError::from_call_site(hresult, call_site);
+                ::crash::set_last_failure(&err);
+                Err(err)
             } else {
                 Ok(())
             }
         }
     }
 }
+
+/// Call a Win32 function that returns zero or null on failure, and turn that
+/// failure into an `Err(WindowsError)` with `GetLastError()`.
+/// On success, this is `Ok` of whatever the function returned.
+macro_rules! win32 {
+    ($call:expr) => {
+        {
+            let ret = $call;
+            if ::error::Win32Return::is_failure(&ret) {
+                // This must come before anything else can overwrite it.
+                let err = ::error::Win32Error::last();
+                let call_site = ::error::CallSite {
+                    file: file!(),
+                    line: line!(),
+                    expr: stringify!($call),
+                };
+                let err = ::error::WindowsError::from_win32_call_site(err, call_site);
+                ::crash::set_last_failure(&err);
+                Err(err)
+            } else {
+                Ok(ret)
+            }
+        }
+    }
+}
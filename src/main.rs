@@ -4,17 +4,27 @@
 // #![deny(warnings)]
 #![allow(dead_code)]
 
+extern crate atty;
+extern crate backtrace;
 extern crate clap;
+extern crate dirs;
 extern crate termcolor;
+extern crate toml;
+#[macro_use]
 extern crate winapi;
 extern crate wio;
 
 use std::{
+    cell::RefCell,
+    io,
     mem,
+    process,
     ptr,
+    time::{Duration, Instant},
 };
 
 use winapi::{
+    shared::windef::HWND,
     um::winuser::*,
     um::winuser,
 };
@@ -22,32 +32,201 @@ use winapi::{
 #[macro_use]
 mod macros;
 mod error;
+mod adapter;
 mod config;
+mod crash;
+mod dred;
+mod fallback;
+mod info_queue;
+mod live_objects;
+mod manifest;
+mod overlay;
+mod recovery;
+mod reload;
+mod trace;
 mod win32_window;
 mod renderer;
 
-fn main() -> Result<(), error::WindowsError> {
+fn main() {
     let conf = config::Config::load();
-    println!("{:#?}\n", conf);
 
-    let h_wnd = win32_window::init_window("Dx12?")?;
+    let catalog = match conf.error_theme {
+        Some(ref path) => {
+            error::load_color_spec_catalog(path).unwrap_or_else(|err| {
+                eprintln!("Ignoring error theme: {}", err);
+                error::get_color_spec_catalog()
+            })
+        },
+        None => error::get_color_spec_catalog(),
+    };
+    error::set_error_style(error::ErrorStyle::new(conf.color, catalog)
+                               .with_format(conf.error_format));
+
+    crash::init(&conf);
+    crash::install_panic_hook();
+    trace::set_enabled(conf.trace_api);
+
+    let result = match conf.command {
+        config::Command::DecodeHresult(hr) => {
+            let stdout = io::stdout();
+            let _ = error::write_hresult_report(&mut stdout.lock(), hr);
+            return;
+        },
+        config::Command::ShowConfigSources => {
+            let stdout = io::stdout();
+            let _ = conf.write_sources(&mut stdout.lock());
+            return;
+        },
+        config::Command::DumpConfig(format) => {
+            let stdout = io::stdout();
+            let _ = match format {
+                config::DumpFormat::Toml => conf.write_toml(&mut stdout.lock()),
+                config::DumpFormat::Json => conf.write_json(&mut stdout.lock()),
+            };
+            return;
+        },
+        config::Command::ListProfiles => {
+            let stdout = io::stdout();
+            let _ = conf.write_profiles(&mut stdout.lock());
+            return;
+        },
+        config::Command::ListAdapters => renderer::print_adapters(&conf),
+        config::Command::ShowCaps     => renderer::print_caps(&conf),
+        config::Command::Run          => run(&conf),
+    };
+    trace::print_summary();
+
+    // Even a failed run is worth reproducing.
+    if let Some(ref path) = conf.manifest {
+        match manifest::write_file(path, &conf) {
+            Ok(())   => eprintln!("Wrote manifest to {}", path.display()),
+            Err(err) => eprintln!("Couldn't write manifest to {}: {}", path.display(), err),
+        }
+    }
+
+    if let Err(err) = result {
+        error::print_error(&err);
+        crash::report_error(&err);
+        process::exit(err.category().exit_code());
+    }
+}
+
+fn run(conf: &config::Config) -> error::WindowsResult<()> {
+    use error::ResultExt;
+
+    {
+        let stdout = io::stdout();
+        let _ = conf.write_toml(&mut stdout.lock());
+        println!();
+    }
+
+    let h_wnd = win32_window::init_window(WINDOW_TITLE,
+                                          conf.resolution.width,
+                                          conf.resolution.height)
+        .context("creating the window")?;
+
+    // What we're running with, which changes when a config file does. If we
+    // lose the device, we recreate it with this.
+    let live_conf = RefCell::new(conf.clone());
+    // What we last loaded, including changes we couldn't apply, so we only
+    // report those once.
+    let mut loaded_conf = conf.clone();
+    let mut watcher = reload::ConfigWatcher::new(config::config_file_paths());
+    let mut stats = overlay::FrameStats::new(Duration::from_millis(500));
 
-    let _r = renderer::Renderer::create(&conf, h_wnd)?;
+    let mut renderer = recovery::Recovery::new(|| {
+            renderer::Renderer::create(&live_conf.borrow(), h_wnd)
+                .context("creating the renderer")
+        })?;
 
     loop {
+        // Handle every waiting message, then draw a frame.
         unsafe {
             let mut msg = mem::zeroed();
-            let ret = GetMessageA(&mut msg,
-                                  ptr::null_mut(), // hWnd
-                                  0,               // wMsgFilterMin
-                                  0);              // wMsgFilterMax
-            if ret == 0 {
-                break;
+            let ret = PeekMessageA(&mut msg,
+                                   ptr::null_mut(), // hWnd
+                                   0,               // wMsgFilterMin
+                                   0,               // wMsgFilterMax
+                                   PM_REMOVE);
+            if ret != 0 {
+                if msg.message == WM_QUIT {
+                    break;
+                }
+                TranslateMessage(&msg);
+                DispatchMessageA(&msg);
+                continue;
             }
-            TranslateMessage(&msg);
-            DispatchMessageA(&msg);
+        }
+
+        if watcher.poll() {
+            reload_config(&live_conf, &mut loaded_conf, h_wnd, &mut renderer)?;
+        }
+
+        renderer.frame()?;
+
+        let overlays = live_conf.borrow().overlays;
+        if stats.frame(Instant::now()) && !overlays.is_empty() {
+            let device = renderer.current().map(|renderer| renderer.attempt().to_string());
+            let title = overlay::title(WINDOW_TITLE,
+                                      overlays,
+                                      &stats,
+                                      device.as_ref().map(String::as_str));
+            // Not worth stopping for.
+            let _ = win32_window::set_title(h_wnd, &title);
         }
     }
 
     Ok(())
 }
+
+const WINDOW_TITLE: &str = "Dx12?";
+
+/// Load the config again, apply what we can to the window and `renderer`,
+/// and report what needs a restart. A bad config file is reported and
+/// otherwise ignored, since it's probably half-saved.
+///
+/// We diff against `loaded_conf`, the last config we loaded, so a change that
+/// needs a restart is only reported when it's made.
+fn reload_config<F>(live_conf:   &RefCell<config::Config>,
+                    loaded_conf: &mut config::Config,
+                    h_wnd:       HWND,
+                    renderer:    &mut recovery::Recovery<renderer::Renderer, F>)
+    -> error::WindowsResult<()>
+    where F: FnMut() -> error::WindowsResult<renderer::Renderer>
+{
+    use error::ResultExt;
+
+    let new_conf = match config::Config::reload() {
+        Ok(new_conf) => new_conf,
+        Err(err)     => {
+            eprintln!("Ignoring config change: {}", err);
+            return Ok(());
+        },
+    };
+    let changes = reload::diff(loaded_conf, &new_conf);
+    reload::report(&changes, &live_conf.borrow(), &new_conf);
+    *loaded_conf = new_conf;
+    if changes.live.is_empty() {
+        return Ok(());
+    }
+
+    live_conf.borrow_mut().apply_live(loaded_conf);
+    let conf = live_conf.borrow();
+    if changes.live.contains(&"resolution") {
+        let resized = win32_window::resize_window(h_wnd,
+                                                  conf.resolution.width,
+                                                  conf.resolution.height);
+        // The swapchain still resizes, so this isn't worth stopping for.
+        if let Err(err) = resized {
+            error::print_error(&err.context("resizing the window"));
+        }
+    }
+    if conf.overlays.is_empty() {
+        // Don't leave the last overlays up.
+        let _ = win32_window::set_title(h_wnd, WINDOW_TITLE);
+    }
+    // Losing the device here is no worse than losing it mid-frame. If we're
+    // between devices, the next one will be made with `conf`.
+    renderer.with_current(|renderer| renderer.apply_live(&conf))
+        .context("applying config changes")
+}
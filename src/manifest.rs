@@ -0,0 +1,276 @@
+
+//! What a run used, so it can be reproduced somewhere else
+//!
+//! `run --manifest FILE` writes the resolved config, along with what we
+//! picked based on it, as TOML. `--replay-config FILE` reads the `[config]`
+//! table back. The other tables are only there to compare against.
+//!
+//! Like `crash`, pieces get filled in as the renderer learns them.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use adapter::AdapterDesc;
+use config::{self, Dx12FeatureLevel};
+
+/// The parts of the swapchain description worth comparing between runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapchainInfo {
+    pub width:        u32,
+    pub height:       u32,
+    /// e.g. `R8G8B8A8_UNORM`
+    pub format:       String,
+    pub buffer_count: u32,
+    pub sample_count: u32,
+    /// e.g. `FLIP_DISCARD`
+    pub swap_effect:  String,
+    pub windowed:     bool,
+}
+
+#[derive(Default)]
+struct RunInfo {
+    /// The adapter's index, or `None` for WARP, and its description.
+    adapter:           Option<(Option<usize>, AdapterDesc)>,
+    /// What we created the device with.
+    feature_level:     Option<Dx12FeatureLevel>,
+    /// The highest the device supports, which can be above `feature_level`.
+    max_feature_level: Option<Dx12FeatureLevel>,
+    swapchain:         Option<SwapchainInfo>,
+}
+
+thread_local! {
+    static RUN_INFO: RefCell<RunInfo> = RefCell::new(RunInfo::default());
+}
+
+/// Record the adapter we created the device on. `index` is `None` for WARP.
+pub fn set_adapter(index: Option<usize>, desc: AdapterDesc) {
+    RUN_INFO.with(|info| info.borrow_mut().adapter = Some((index, desc)));
+}
+
+/// Record the feature level the device was created with, and the highest
+/// one it supports.
+pub fn set_feature_levels(feature_level: Dx12FeatureLevel, max_feature_level: Dx12FeatureLevel) {
+    RUN_INFO.with(|info| {
+        let mut info = info.borrow_mut();
+        info.feature_level     = Some(feature_level);
+        info.max_feature_level = Some(max_feature_level);
+    });
+}
+
+pub fn set_swapchain(swapchain: SwapchainInfo) {
+    RUN_INFO.with(|info| info.borrow_mut().swapchain = Some(swapchain));
+}
+
+/// Write a manifest to `path`, replacing whatever was there.
+pub fn write_file(path: &Path, config: &config::Config) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    write(&mut file, config)
+}
+
+/// Write everything recorded so far, with `config`.
+///
+/// ```toml
+/// # dx12 run manifest. Reproduce this run with:
+/// #     dx12 --replay-config <this file>
+///
+/// [build]
+/// version = "0.1.0"
+/// ...
+///
+/// [config]
+/// force_warp = false
+/// ...
+///
+/// [adapter]
+/// index                  = 0
+/// description            = "NVIDIA GeForce GTX 1080"
+/// ...
+/// ```
+///
+/// Tables for things we never got to, like a swapchain after device
+/// creation failed, are left out.
+pub fn write<W: Write>(w: &mut W, config: &config::Config) -> io::Result<()> {
+    RUN_INFO.with(|info| {
+        // If we're somehow already borrowed, write what we can without it.
+        let borrowed = info.try_borrow().ok();
+        write_run(w, config, borrowed.as_ref().map(|info| &**info))
+    })
+}
+
+fn write_run<W: Write>(w: &mut W, config: &config::Config, info: Option<&RunInfo>)
+    -> io::Result<()>
+{
+    use toml::Value;
+
+    writeln!(w, "# dx12 run manifest. Reproduce this run with:")?;
+    writeln!(w, "#     dx12 --replay-config <this file>")?;
+
+    let profile = if cfg!(debug_assertions) { "debug" } else { "release" };
+    writeln!(w)?;
+    writeln!(w, "[build]")?;
+    writeln!(w, "version = {}", Value::String(env!("CARGO_PKG_VERSION").to_string()))?;
+    writeln!(w, "profile = {}", Value::String(profile.to_string()))?;
+    writeln!(w, "os      = {}", Value::String(::std::env::consts::OS.to_string()))?;
+    writeln!(w, "arch    = {}", Value::String(::std::env::consts::ARCH.to_string()))?;
+
+    writeln!(w)?;
+    writeln!(w, "[config]")?;
+    config.write_toml(w)?;
+
+    let info = match info {
+        Some(info) => info,
+        None       => return Ok(()),
+    };
+
+    if let Some((index, ref desc)) = info.adapter {
+        writeln!(w)?;
+        writeln!(w, "[adapter]")?;
+        match index {
+            Some(index) => writeln!(w, "index                  = {}", index)?,
+            None        => writeln!(w, "warp                   = true")?,
+        }
+        writeln!(w, "description            = {}", Value::String(desc.description.to_string()))?;
+        writeln!(w, "vendor                 = {}", Value::String(desc.vendor.to_string()))?;
+        writeln!(w, "device_id              = \"0x{:x}\"", desc.device_id)?;
+        writeln!(w, "luid                   = \"{}\"", desc.luid)?;
+        writeln!(w, "dedicated_video_memory = {}", desc.dedicated_video_memory)?;
+        writeln!(w, "shared_system_memory   = {}", desc.shared_system_memory)?;
+        writeln!(w, "software               = {}", desc.software)?;
+    }
+
+    if let Some(feature_level) = info.feature_level {
+        writeln!(w)?;
+        writeln!(w, "[device]")?;
+        writeln!(w, "feature_level     = \"{}\"", feature_level)?;
+        if let Some(max_feature_level) = info.max_feature_level {
+            writeln!(w, "max_feature_level = \"{}\"", max_feature_level)?;
+        }
+    }
+
+    if let Some(ref swapchain) = info.swapchain {
+        writeln!(w)?;
+        writeln!(w, "[swapchain]")?;
+        writeln!(w, "width        = {}", swapchain.width)?;
+        writeln!(w, "height       = {}", swapchain.height)?;
+        writeln!(w, "format       = {}", Value::String(swapchain.format.to_string()))?;
+        writeln!(w, "buffer_count = {}", swapchain.buffer_count)?;
+        writeln!(w, "sample_count = {}", swapchain.sample_count)?;
+        writeln!(w, "swap_effect  = {}", Value::String(swapchain.swap_effect.to_string()))?;
+        writeln!(w, "windowed     = {}", swapchain.windowed)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        env,
+        fs,
+    };
+
+    use adapter::fixtures::laptop;
+    use config::Dx12FeatureLevel::*;
+
+    fn manifest(config: &config::Config, info: Option<&RunInfo>) -> String {
+        let mut text = vec![];
+        write_run(&mut text, config, info).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn writes_what_the_run_picked() {
+        let info = RunInfo {
+            adapter:           Some((Some(1), laptop()[1].clone())),
+            feature_level:     Some(E11_0),
+            max_feature_level: Some(E12_1),
+            swapchain:         Some(SwapchainInfo {
+                width:        1024,
+                height:       768,
+                format:       "R8G8B8A8_UNORM".to_string(),
+                buffer_count: 2,
+                sample_count: 1,
+                swap_effect:  "FLIP_DISCARD".to_string(),
+                windowed:     true,
+            }),
+        };
+        let text = manifest(&config::Config::default(), Some(&info));
+        let tables = text.find("\n[adapter]\n").expect("an [adapter] table");
+        assert_eq!(&text[tables..], "
+[adapter]
+index                  = 1
+description            = \"NVIDIA GeForce GTX 1080\"
+vendor                 = \"Nvidia\"
+device_id              = \"0x0\"
+luid                   = \"0x0000000000002000\"
+dedicated_video_memory = 8589934592
+shared_system_memory   = 8589934592
+software               = false
+
+[device]
+feature_level     = \"11_0\"
+max_feature_level = \"12_1\"
+
+[swapchain]
+width        = 1024
+height       = 768
+format       = \"R8G8B8A8_UNORM\"
+buffer_count = 2
+sample_count = 1
+swap_effect  = \"FLIP_DISCARD\"
+windowed     = true
+");
+    }
+
+    #[test]
+    fn leaves_out_what_we_never_got_to() {
+        let info = RunInfo {
+            adapter: Some((None, laptop()[2].clone())),
+            ..RunInfo::default()
+        };
+        let text = manifest(&config::Config::default(), Some(&info));
+        assert!(text.contains("\n[adapter]\nwarp                   = true\n"), "{}", text);
+        assert!(!text.contains("[device]"));
+        assert!(!text.contains("[swapchain]"));
+
+        let text = manifest(&config::Config::default(), None);
+        assert!(text.contains("\n[config]\n"));
+        assert!(!text.contains("[adapter]"));
+    }
+
+    #[test]
+    fn replays_the_config() {
+        let mut config = config::Config::default();
+        config.apply_toml("vsync = false\nresolution = \"800x600\"\nadapter = \"nvidia\"\n\
+                           feature_level = \"11_0..12_1\"\n",
+                          Path::new("dx12.toml"))
+            .unwrap();
+        let info = RunInfo {
+            adapter:       Some((Some(1), laptop()[1].clone())),
+            feature_level: Some(E11_0),
+            ..RunInfo::default()
+        };
+
+        let name = format!("dx12-manifest-test-{}.toml", ::std::process::id());
+        let path = env::temp_dir().join(name);
+        fs::write(&path, manifest(&config, Some(&info))).unwrap();
+        let mut replayed = config::Config::default();
+        let result = replayed.apply_replay(&path);
+        let _ = fs::remove_file(&path);
+        result.unwrap();
+
+        let toml = |config: &config::Config| {
+            let mut text = vec![];
+            config.write_toml(&mut text).unwrap();
+            String::from_utf8(text).unwrap()
+        };
+        assert_eq!(toml(&replayed), toml(&config));
+        assert!(!replayed.vsync);
+        assert_eq!(replayed.adapter.to_string(), "nvidia");
+    }
+}
@@ -0,0 +1,184 @@
+
+//! Debug overlays, shown in the window title
+//!
+//! We don't draw text yet, so the title bar is the overlay. `run` keeps a
+//! `FrameStats` and asks `title()` what to show, which means none of this
+//! needs a window to try out.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Which overlays to show.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Overlays {
+    /// Frames per second, and milliseconds per frame.
+    pub fps:     bool,
+    /// The adapter and feature level we created the device with.
+    pub adapter: bool,
+}
+
+impl Overlays {
+    pub fn none() -> Overlays {
+        Overlays { fps: false, adapter: false }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Overlays::none()
+    }
+}
+
+impl Default for Overlays {
+    fn default() -> Overlays {
+        Overlays::none()
+    }
+}
+
+impl fmt::Display for Overlays {
+    /// The overlays we show, like `fps,adapter`, or `none`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let overlays: Vec<&str> = [("fps",     self.fps),
+                                   ("adapter", self.adapter)]
+            .iter()
+            .filter(|&&(_, enabled)| enabled)
+            .map(|&(overlay, _)| overlay)
+            .collect();
+        if overlays.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", overlays.join(","))
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidOverlays;
+
+impl FromStr for Overlays {
+    type Err = InvalidOverlays;
+
+    /// Overlays separated by `,`, in any order, or `none`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("none") {
+            return Ok(Overlays::none());
+        }
+        let mut overlays = Overlays::none();
+        for overlay in s.split(',').map(|overlay| overlay.trim().to_lowercase()) {
+            match overlay.as_str() {
+                "fps"     => overlays.fps     = true,
+                "adapter" => overlays.adapter = true,
+                _         => return Err(InvalidOverlays),
+            }
+        }
+        Ok(overlays)
+    }
+}
+
+/// Counts frames, and works out a frame rate every `interval`.
+#[derive(Debug, Clone)]
+pub struct FrameStats {
+    interval: Duration,
+    start:    Instant,
+    frames:   u32,
+    /// Frames per second over the last whole interval.
+    fps:      Option<f64>,
+}
+
+impl FrameStats {
+    pub fn new(interval: Duration) -> FrameStats {
+        FrameStats { interval, start: Instant::now(), frames: 0, fps: None }
+    }
+
+    /// Count a frame that finished at `now`. Returns true when the frame
+    /// rate changed, so the title is worth updating.
+    pub fn frame(&mut self, now: Instant) -> bool {
+        self.frames += 1;
+        let elapsed = now.duration_since(self.start);
+        if elapsed < self.interval {
+            return false;
+        }
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.fps = Some(self.frames as f64 / seconds);
+        self.start = now;
+        self.frames = 0;
+        true
+    }
+
+    pub fn fps(&self) -> Option<f64> {
+        self.fps
+    }
+}
+
+/// The window title for `base` with `overlays`, e.g.
+/// `Dx12? | 59.9 fps (16.69 ms) | adapter 0 (NVIDIA GeForce GTX 1080) at 12_0`.
+///
+/// `device` is what `fallback::Attempt` says about the device, if we have one.
+pub fn title(base: &str, overlays: Overlays, stats: &FrameStats, device: Option<&str>) -> String {
+    let mut title = base.to_string();
+    if overlays.fps {
+        match stats.fps() {
+            Some(fps) if fps > 0.0 => {
+                title += &format!(" | {:.1} fps ({:.2} ms)", fps, 1000.0 / fps);
+            },
+            _ => title += " | -- fps",
+        }
+    }
+    if overlays.adapter {
+        if let Some(device) = device {
+            title += &format!(" | {}", device);
+        }
+    }
+    title
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_displays_overlays() {
+        let both = Overlays { fps: true, adapter: true };
+        assert_eq!("adapter, FPS".parse(), Ok(both));
+        assert_eq!("fps".parse(), Ok(Overlays { fps: true, adapter: false }));
+        assert_eq!(" None ".parse(), Ok(Overlays::none()));
+        assert_eq!("fps,clock".parse::<Overlays>(), Err(InvalidOverlays));
+        assert_eq!("".parse::<Overlays>(), Err(InvalidOverlays));
+
+        assert_eq!(both.to_string(), "fps,adapter");
+        assert_eq!(Overlays::none().to_string(), "none");
+        assert_eq!(both.to_string().parse(), Ok(both));
+    }
+
+    /// Stats that saw 60 frames in the last second.
+    fn sixty_fps() -> FrameStats {
+        let mut stats = FrameStats::new(Duration::from_secs(1));
+        let start = stats.start;
+        for frame in 1..60 {
+            assert!(!stats.frame(start + Duration::from_millis(frame * 16)));
+        }
+        assert!(stats.frame(start + Duration::from_secs(1)));
+        stats
+    }
+
+    #[test]
+    fn counts_frames_over_each_interval() {
+        let stats = FrameStats::new(Duration::from_secs(1));
+        assert_eq!(stats.fps(), None);
+        assert_eq!(sixty_fps().fps(), Some(60.0));
+    }
+
+    #[test]
+    fn titles_show_the_overlays_asked_for() {
+        let device = Some("adapter 0 (NVIDIA GeForce GTX 1080) at 12_0");
+        let both = Overlays { fps: true, adapter: true };
+
+        assert_eq!(title("Dx12?", Overlays::none(), &sixty_fps(), device), "Dx12?");
+        assert_eq!(title("Dx12?", both, &sixty_fps(), device),
+                   "Dx12? | 60.0 fps (16.67 ms) | adapter 0 (NVIDIA GeForce GTX 1080) at 12_0");
+        // Before the first interval, and before there's a device.
+        let stats = FrameStats::new(Duration::from_secs(1));
+        assert_eq!(title("Dx12?", both, &stats, None), "Dx12? | -- fps");
+    }
+}
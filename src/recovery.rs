@@ -0,0 +1,361 @@
+
+//! Surviving device removal
+//!
+//! Driver updates, TDRs, and the like take the device away from us. Every
+//! object we made with it goes too, so to recover we throw the whole renderer
+//! away and make a new one.
+
+use std::{
+    thread,
+    time::Duration,
+};
+
+use error::*;
+
+/// Something that draws frames until it loses its device.
+pub trait Recoverable: Sized {
+    /// Update and draw one frame.
+    fn frame(&mut self) -> WindowsResult<()>;
+}
+
+#[derive(Debug, Clone)]
+pub enum RecoveryState {
+    /// Drawing frames like normal.
+    Running,
+    /// We lost the device with this error, and have to recreate it.
+    DeviceLost(WindowsError),
+    /// Recreating failed with this error, so we gave up.
+    Failed(WindowsError),
+}
+
+/// Owns a `Recoverable`, and replaces it when its device is lost.
+pub struct Recovery<R, F> {
+    create:       F,
+    current:      Option<R>,
+    state:        RecoveryState,
+    max_attempts: u32,
+    retry_delay:  Duration,
+    recoveries:   u32,
+}
+
+impl<R, F> Recovery<R, F>
+    where R: Recoverable,
+          F: FnMut() -> WindowsResult<R>
+{
+    /// Make the first one with `create`. If that fails, we don't retry.
+    pub fn new(mut create: F) -> WindowsResult<Recovery<R, F>> {
+        let current = create()?;
+        Ok(Recovery {
+            create,
+            current:      Some(current),
+            state:        RecoveryState::Running,
+            max_attempts: 3,
+            retry_delay:  Duration::from_millis(500),
+            recoveries:   0,
+        })
+    }
+
+    /// How many times to try `create` after losing the device.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Recovery<R, F> {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// How long to wait between attempts. Drivers can take a while to reset.
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Recovery<R, F> {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn state(&self) -> &RecoveryState {
+        &self.state
+    }
+
+    /// How many times we've successfully recovered.
+    pub fn recoveries(&self) -> u32 {
+        self.recoveries
+    }
+
+    pub fn current(&self) -> Option<&R> {
+        self.current.as_ref()
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut R> {
+        self.current.as_mut()
+    }
+
+    /// Draw a frame, recovering first if we need to.
+    ///
+    /// Losing the device mid-frame isn't an error unless we can't recover.
+    /// Any other error is passed along as-is.
+    pub fn frame(&mut self) -> WindowsResult<()> {
+        match self.state {
+            RecoveryState::Running         => {},
+            RecoveryState::DeviceLost(_)   => self.recreate()?,
+            RecoveryState::Failed(ref err) => return Err(err.clone()),
+        }
+
+        self.with_current(|current| current.frame())
+    }
+
+    /// Call `f` with the current one, recovering if it loses the device
+    /// like `frame()` does. Between devices, there's nothing to call it with.
+    pub fn with_current<G>(&mut self, f: G) -> WindowsResult<()>
+        where G: FnOnce(&mut R) -> WindowsResult<()>
+    {
+        let result = match self.current {
+            Some(ref mut current) => f(current),
+            None                  => return Ok(()),
+        };
+        match result {
+            Err(err) => {
+                if !is_device_lost(&err) {
+                    return Err(err);
+                }
+                eprintln!("Lost the device ({}), recreating it", err.kind());
+                self.lose_device(err);
+                self.recreate()
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn lose_device(&mut self, err: WindowsError) {
+        // Dropping it releases everything made with the old device.
+        self.current = None;
+        self.state = RecoveryState::DeviceLost(err);
+    }
+
+    fn recreate(&mut self) -> WindowsResult<()> {
+        let mut attempt = 1;
+        loop {
+            match (self.create)() {
+                Ok(current) => {
+                    self.current = Some(current);
+                    self.state = RecoveryState::Running;
+                    self.recoveries += 1;
+                    return Ok(());
+                },
+                Err(err) => {
+                    let context = format!("recreating the device (attempt {} of {})",
+                                          attempt,
+                                          self.max_attempts);
+                    let err = err.context(context);
+                    // The device can still look removed while the driver resets.
+                    let retry = is_device_lost(&err) && attempt < self.max_attempts;
+                    if !retry {
+                        self.state = RecoveryState::Failed(err.clone());
+                        return Err(err);
+                    }
+                    print_error(&err);
+                    thread::sleep(self.retry_delay);
+                    attempt += 1;
+                },
+            }
+        }
+    }
+}
+
+/// Whether `err` means the device went away, even if it was put in another
+/// category on the way up.
+fn is_device_lost(err: &WindowsError) -> bool {
+    err.category() == Category::DeviceRemoved ||
+    err.hresult().map_or(false, HResult::is_device_lost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        cell::Cell,
+        mem,
+        rc::Rc,
+    };
+
+    const DEVICE_REMOVED: i32 = 0x887A0005u32 as i32;
+    const DEVICE_RESET:   i32 = 0x887A0007u32 as i32;
+    const INVALIDARG:     i32 = 0x80070057u32 as i32;
+
+    /// Fails its frames with `errors`, in order, then draws them fine.
+    struct Fake {
+        /// Which fake this is, counting from 1.
+        id:     u32,
+        errors: Vec<i32>,
+    }
+
+    impl Recoverable for Fake {
+        fn frame(&mut self) -> WindowsResult<()> {
+            match self.errors.pop() {
+                Some(hr) => Err(WindowsError::from(hr)),
+                None     => Ok(()),
+            }
+        }
+    }
+
+    /// A `Recovery` over fakes. The first fails its frames with
+    /// `frame_errors`, and creating the ones after it fails with
+    /// `create_errors` until we run out. Also returns how many we've made.
+    fn recovery(frame_errors: &[i32], create_errors: &[i32])
+        -> (Recovery<Fake, impl FnMut() -> WindowsResult<Fake>>, Rc<Cell<u32>>)
+    {
+        let created = Rc::new(Cell::new(0));
+        let counter = created.clone();
+        let mut frame_errors: Vec<i32> = frame_errors.iter().rev().cloned().collect();
+        let mut create_errors: Vec<i32> = create_errors.iter().rev().cloned().collect();
+        let recovery = Recovery::new(move || {
+                if counter.get() > 0 {
+                    if let Some(hr) = create_errors.pop() {
+                        return Err(WindowsError::from(hr));
+                    }
+                }
+                counter.set(counter.get() + 1);
+                Ok(Fake { id: counter.get(), errors: mem::replace(&mut frame_errors, vec![]) })
+            })
+            .expect("the first fake is always created")
+            .with_retry_delay(Duration::from_millis(0));
+        (recovery, created)
+    }
+
+    fn current_id<F>(recovery: &Recovery<Fake, F>) -> Option<u32>
+        where F: FnMut() -> WindowsResult<Fake>
+    {
+        recovery.current().map(|fake| fake.id)
+    }
+
+    fn is_running<F>(recovery: &Recovery<Fake, F>) -> bool
+        where F: FnMut() -> WindowsResult<Fake>
+    {
+        match *recovery.state() {
+            RecoveryState::Running => true,
+            _                      => false,
+        }
+    }
+
+    #[test]
+    fn recreates_after_losing_the_device() {
+        for &hr in &[DEVICE_REMOVED, DEVICE_RESET] {
+            let (mut recovery, created) = recovery(&[hr], &[]);
+            assert_eq!(current_id(&recovery), Some(1));
+
+            assert!(recovery.frame().is_ok());
+            assert!(is_running(&recovery));
+            assert_eq!(current_id(&recovery), Some(2));
+            assert_eq!(recovery.recoveries(), 1);
+            assert_eq!(created.get(), 2);
+
+            assert!(recovery.frame().is_ok());
+            assert_eq!(current_id(&recovery), Some(2));
+        }
+    }
+
+    #[test]
+    fn device_lost_until_recreated() {
+        let (mut recovery, _) = recovery(&[], &[]);
+        recovery.lose_device(WindowsError::from(DEVICE_REMOVED));
+        match *recovery.state() {
+            RecoveryState::DeviceLost(ref err) => {
+                assert_eq!(err.hresult(), Some(HResult(DEVICE_REMOVED)));
+            },
+            ref state => panic!("expected DeviceLost, got {:?}", state),
+        }
+        assert_eq!(current_id(&recovery), None);
+
+        assert!(recovery.frame().is_ok());
+        assert!(is_running(&recovery));
+        assert_eq!(current_id(&recovery), Some(2));
+    }
+
+    #[test]
+    fn retries_while_the_device_looks_removed() {
+        let (mut recovery, created) = recovery(&[DEVICE_REMOVED], &[DEVICE_RESET, DEVICE_REMOVED]);
+        assert!(recovery.frame().is_ok());
+        assert!(is_running(&recovery));
+        assert_eq!(current_id(&recovery), Some(2));
+        assert_eq!(recovery.recoveries(), 1);
+        assert_eq!(created.get(), 2);
+    }
+
+    #[test]
+    fn fails_when_retries_run_out() {
+        let (recovery, created) = recovery(&[DEVICE_REMOVED], &[DEVICE_REMOVED; 3]);
+        let mut recovery = recovery.with_max_attempts(3);
+        let err = recovery.frame().unwrap_err();
+        assert_eq!(err.hresult(), Some(HResult(DEVICE_REMOVED)));
+        assert_eq!(err.context_chain().last().map(|s| s.as_str()),
+                   Some("recreating the device (attempt 3 of 3)"));
+        match *recovery.state() {
+            RecoveryState::Failed(ref failed) => {
+                assert_eq!(failed.hresult(), Some(HResult(DEVICE_REMOVED)));
+            },
+            ref state => panic!("expected Failed, got {:?}", state),
+        }
+        assert_eq!(current_id(&recovery), None);
+        assert_eq!(recovery.recoveries(), 0);
+
+        // We gave up, so we don't try again.
+        assert!(recovery.frame().is_err());
+        assert_eq!(created.get(), 1);
+    }
+
+    #[test]
+    fn other_create_errors_fail_at_once() {
+        let (mut recovery, _) = recovery(&[DEVICE_REMOVED], &[INVALIDARG]);
+        let err = recovery.frame().unwrap_err();
+        assert_eq!(err.hresult(), Some(HResult(INVALIDARG)));
+        assert_eq!(err.context_chain().last().map(|s| s.as_str()),
+                   Some("recreating the device (attempt 1 of 3)"));
+        match *recovery.state() {
+            RecoveryState::Failed(_) => {},
+            ref state                => panic!("expected Failed, got {:?}", state),
+        }
+    }
+
+    #[test]
+    fn retries_device_lost_errors_in_another_category() {
+        let mut create_errors = vec![DEVICE_REMOVED];
+        let mut created = 0;
+        let mut recovery = Recovery::new(|| {
+                if created > 0 {
+                    if let Some(hr) = create_errors.pop() {
+                        let err = WindowsError::from(hr).with_category(Category::DeviceCreation);
+                        return Err(err);
+                    }
+                }
+                created += 1;
+                Ok(Fake { id: created, errors: vec![] })
+            })
+            .expect("the first fake is always created")
+            .with_retry_delay(Duration::from_millis(0));
+
+        let lost = WindowsError::from(DEVICE_REMOVED).with_category(Category::Other);
+        assert!(recovery.with_current(|_| Err(lost)).is_ok());
+        assert!(is_running(&recovery));
+        assert_eq!(current_id(&recovery), Some(2));
+    }
+
+    #[test]
+    fn with_current_recovers_like_frame() {
+        let (mut recovery, _) = recovery(&[], &[]);
+        assert!(recovery.with_current(|_| Err(WindowsError::from(DEVICE_REMOVED))).is_ok());
+        assert!(is_running(&recovery));
+        assert_eq!(current_id(&recovery), Some(2));
+
+        let err = recovery.with_current(|_| Err(WindowsError::from(INVALIDARG))).unwrap_err();
+        assert_eq!(err.hresult(), Some(HResult(INVALIDARG)));
+        assert_eq!(current_id(&recovery), Some(2));
+    }
+
+    #[test]
+    fn other_frame_errors_pass_through() {
+        let (mut recovery, created) = recovery(&[INVALIDARG], &[]);
+        let err = recovery.frame().unwrap_err();
+        assert_eq!(err.hresult(), Some(HResult(INVALIDARG)));
+        assert!(is_running(&recovery));
+        assert_eq!(current_id(&recovery), Some(1));
+        assert_eq!(recovery.recoveries(), 0);
+
+        assert!(recovery.frame().is_ok());
+        assert_eq!(created.get(), 1);
+    }
+}
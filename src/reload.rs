@@ -0,0 +1,199 @@
+
+//! Picking up config file changes while running
+//!
+//! `ConfigWatcher` notices when a config file is saved, and `diff()` sorts
+//! what changed into keys we can apply without restarting, `LIVE_KEYS`, and
+//! everything else, which we only report. Neither needs a window or device,
+//! so `run` does the applying.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use config::{self, Config};
+
+/// Polls config files' modification times. We don't need to know the moment
+/// a file changes, so this beats a platform-specific file watcher.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    paths:     Vec<PathBuf>,
+    /// When each of `paths` was last modified, or `None` if it didn't exist.
+    modified:  Vec<Option<SystemTime>>,
+    interval:  Duration,
+    last_poll: Instant,
+}
+
+impl ConfigWatcher {
+    /// Watch `paths`, which don't have to exist yet.
+    pub fn new(paths: Vec<PathBuf>) -> ConfigWatcher {
+        let modified = paths.iter().map(|path| modified(path)).collect();
+        ConfigWatcher {
+            paths,
+            modified,
+            interval:  Duration::from_millis(500),
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// How often `poll()` actually looks at the files.
+    pub fn with_interval(mut self, interval: Duration) -> ConfigWatcher {
+        self.interval = interval;
+        self
+    }
+
+    /// Whether any file was created, changed, or deleted since we last
+    /// looked. Cheap enough to call every frame.
+    pub fn poll(&mut self) -> bool {
+        if self.last_poll.elapsed() < self.interval {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = false;
+        for (path, last) in self.paths.iter().zip(self.modified.iter_mut()) {
+            let now = modified(path);
+            if now != *last {
+                *last = now;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// The keys whose values differ between two configs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// Keys in `config::LIVE_KEYS`.
+    pub live:    Vec<&'static str>,
+    /// Keys that only take effect when we start.
+    pub restart: Vec<&'static str>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty() && self.restart.is_empty()
+    }
+}
+
+/// What changed from `old` to `new`, in `config::KEYS` order.
+pub fn diff(old: &Config, new: &Config) -> Changes {
+    let mut changes = Changes::default();
+    for &key in config::KEYS {
+        if old.get(key) == new.get(key) {
+            continue;
+        }
+        if config::LIVE_KEYS.contains(&key) {
+            changes.live.push(key);
+        } else {
+            changes.restart.push(key);
+        }
+    }
+    changes
+}
+
+/// Tell whoever's watching stderr what we applied, and what we didn't.
+/// `running` is what we're running with, which can be older than what the
+/// changes were diffed against.
+///
+/// ```text
+/// Config changed: applied vsync = false, resolution = "1280x720"
+/// Config changed: restart to apply adapter = "1" (using "0")
+/// ```
+pub fn report(changes: &Changes, running: &Config, new: &Config) {
+    let stderr = io::stderr();
+    // If we can't write to stderr, there's nowhere left to complain.
+    let _ = write_report(&mut stderr.lock(), changes, running, new);
+}
+
+/// What `report()` writes.
+pub fn write_report<W: Write>(w:       &mut W,
+                              changes: &Changes,
+                              running: &Config,
+                              new:     &Config)
+    -> io::Result<()>
+{
+    let show = |config: &Config, key: &str| match config.get(key) {
+        Some(value) => value.to_string(),
+        None        => "<unset>".to_string(),
+    };
+    if !changes.live.is_empty() {
+        let applied: Vec<String> = changes.live
+            .iter()
+            .map(|key| format!("{} = {}", key, show(new, key)))
+            .collect();
+        writeln!(w, "Config changed: applied {}", applied.join(", "))?;
+    }
+    for key in &changes.restart {
+        if running.get(key) == new.get(key) {
+            // Changed back to what we're running with.
+            continue;
+        }
+        writeln!(w, "Config changed: restart to apply {} = {} (using {})",
+                 key, show(new, key), show(running, key))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        let mut config = Config::default();
+        config.apply_toml(toml, Path::new("dx12.toml")).unwrap();
+        config
+    }
+
+    fn report(changes: &Changes, running: &Config, new: &Config) -> String {
+        let mut text = vec![];
+        write_report(&mut text, changes, running, new).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn nothing_changed() {
+        let old = config("vsync = false\n");
+        assert!(diff(&old, &old.clone()).is_empty());
+        assert_eq!(report(&Changes::default(), &old, &old), "");
+    }
+
+    #[test]
+    fn live_keys_are_applied() {
+        let old = config("vsync = true\n");
+        let new = config("vsync = false\nresolution = \"1280x720\"\n");
+        let changes = diff(&old, &new);
+        assert_eq!(changes, Changes { live: vec!["resolution", "vsync"], restart: vec![] });
+        assert_eq!(report(&changes, &old, &new),
+                   "Config changed: applied resolution = \"1280x720\", vsync = false\n");
+    }
+
+    #[test]
+    fn other_keys_need_a_restart() {
+        let old = config("adapter = \"0\"\n");
+        let new = config("adapter = \"1\"\nvsync = false\n");
+        let changes = diff(&old, &new);
+        assert_eq!(changes, Changes { live: vec!["vsync"], restart: vec!["adapter"] });
+        assert_eq!(report(&changes, &old, &new),
+                   "Config changed: applied vsync = false\n\
+                    Config changed: restart to apply adapter = \"1\" (using \"0\")\n");
+    }
+
+    #[test]
+    fn a_restart_key_changed_back_is_not_reported() {
+        let running = config("adapter = \"0\"\n");
+        // The last reload changed it, and this one changes it back.
+        let loaded = config("adapter = \"1\"\n");
+        let new = config("adapter = \"0\"\n");
+        let changes = diff(&loaded, &new);
+        assert_eq!(changes.restart, ["adapter"]);
+        assert_eq!(report(&changes, &running, &new), "");
+    }
+}
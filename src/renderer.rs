@@ -1,6 +1,6 @@
 
 use std::{
-    fmt,
+    cmp,
     mem,
     ptr,
 };
@@ -9,6 +9,7 @@ use wio::com::ComPtr;
 
 use winapi::{
     Interface,
+    shared::minwindef::BOOL,
     shared::winerror,
     shared::ntdef::HANDLE,
     shared::windef::HWND,
@@ -23,53 +24,27 @@ use winapi::{
 
     um::d3d12::*,
     um::d3d12sdklayers::*,
-    um::dxgidebug::*,
+    um::synchapi::WaitForSingleObject,
+    um::winbase::INFINITE,
 };
 
+use adapter::{
+    vid_to_vendor,
+    AdapterDesc,
+    Luid,
+};
 use config;
+use crash;
+use dred;
+use fallback::{self, AdapterChoice, Attempt};
+use info_queue::InfoQueue;
+use live_objects::LeakCheck;
+use manifest::{self, SwapchainInfo};
+use recovery::Recoverable;
 use error::*;
 
 const FRAME_COUNT: usize = 3;
 
-enum Vendor {
-    Amd,            // 0x1002
-    Imgtec,         // 0x1010
-    Nvidia,         // 0x10DE
-    Arm,            // 0x13B5
-    Qualcomm,       // 0x5143
-    Intel,          // 0x8086
-    Microsoft,      // 0x1414
-    Unknown(u32),
-}
-
-fn vid_to_vendor(vid: u32) -> Vendor {
-    match vid {
-        0x1002 => Vendor::Amd,
-        0x1010 => Vendor::Imgtec,
-        0x10DE => Vendor::Nvidia,
-        0x13B5 => Vendor::Arm,
-        0x5143 => Vendor::Qualcomm,
-        0x8086 => Vendor::Intel,
-        0x1414 => Vendor::Microsoft,
-        _      => Vendor::Unknown(vid),
-    }
-}
-
-impl ::std::fmt::Display for Vendor {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Vendor::Amd          => write!(f, "Amd"),
-            Vendor::Imgtec       => write!(f, "Imgtec"),
-            Vendor::Nvidia       => write!(f, "Nvidia"),
-            Vendor::Arm          => write!(f, "Arm"),
-            Vendor::Qualcomm     => write!(f, "Qualcomm"),
-            Vendor::Intel        => write!(f, "Intel"),
-            Vendor::Microsoft    => write!(f, "Microsoft"),
-            Vendor::Unknown(vid) => write!(f, "Unknown (0x{:x})", vid),
-        }
-    }
-}
-
 #[repr(C)]
 pub struct Vertex {
     position: [f32; 3],
@@ -97,7 +72,9 @@ pub struct Renderer {
     scissor:                        D3D12_RECT,
     swapchain:                      ComPtr<IDXGISwapChain3>,
     device:                         ComPtr<ID3D12Device>,
-    per_frame:                      [PerFrame; FRAME_COUNT],
+    // Empty while we resize the swapchain, which needs every reference to
+    // its buffers released first.
+    per_frame:                      Vec<PerFrame>,
     cmd_alloc:                      ComPtr<ID3D12CommandAllocator>,
     cmd_queue:                      ComPtr<ID3D12CommandQueue>,
     root_sig:                       ComPtr<ID3D12RootSignature>,
@@ -115,30 +92,79 @@ pub struct Renderer {
     fence_event:                    HANDLE,
     fence:                          ComPtr<ID3D12Fence>,
     fence_value:                    u64,
+
+    // ---- Settings we can change while running --------
+    vsync:                          bool,
+    clear_color:                    [f32; 4],
+
+    // ---- Debugging --------
+    /// The adapter and feature level we created the device with.
+    attempt:                        Attempt,
+    info_queue:                     Option<InfoQueue>,
+    // Fields are dropped in order, so this has to stay last to see
+    // everything above released.
+    leak_check:                     Option<LeakCheck>,
 }
 
 impl Renderer {
     /// Initialize a renderer, or return an error describing why we couldn't.
     pub fn create(config: &config::Config,
                   h_wnd:  HWND) -> Result<Renderer, WindowsError> {
+        // Locals are dropped in reverse, so this reports after everything
+        // below is released if we fail.
+        let mut leak_check = None;
         if config.enable_debug {
-            init_debug_objects()?;
+            init_debug_objects().context("enabling the debug layer")?;
+            leak_check = LeakCheck::new()
+                .map_err(|err| print_error(&err.context("setting up live object reporting")))
+                .ok();
         }
 
-        let dxgi_factory = init_dxgi_factory()?;
+        let dxgi_factory = init_dxgi_factory().context("creating the DXGI factory")?;
 
-        let warp_adapter = init_warp_adapter(&dxgi_factory)?;
-        let adapters = enum_adapters(&dxgi_factory)?;
+        let adapters = enum_adapters(&dxgi_factory).context("enumerating adapters")?;
+        print_adapter_list(&adapters)?;
 
-        let adapter: ComPtr<IDXGIAdapter>;
-        if config.force_warp {
-            adapter = warp_adapter;
+        let (adapter, device, attempt) = create_device(config, &dxgi_factory, &adapters)?;
+        crash::set_adapter(format_adapter_desc(&get_adapter_desc(&adapter)?));
+        let adapter1: ComPtr<IDXGIAdapter1> = adapter.cast()?;
+        let index = match attempt.adapter {
+            AdapterChoice::Index(i) => Some(i),
+            AdapterChoice::Warp     => None,
+        };
+        manifest::set_adapter(index, to_adapter_desc(&get_adapter_desc1(&adapter1)?));
+        let feature_level = max_supported_feature_level(&device, attempt_range(config, &attempt))
+            .context("checking supported feature levels")?;
+        println!("Feature level: {} (the highest of {})\n", feature_level, config.feature_level);
+        manifest::set_feature_levels(attempt.feature_level, feature_level);
+
+        let info_queue = if config.enable_debug {
+            InfoQueue::new(&device, config.debug_filter.clone(), config.debug_break)
+                .map_err(|err| print_error(&err.context("getting the info queue")))
+                .ok()
         } else {
-            adapter = adapters[0].clone().cast()?;
+            None
+        };
+
+        let renderer = Renderer::create_on_device(config, h_wnd, &dxgi_factory, &device)
+            .map_err(|err| report_device_removed(&device, err));
+        if let Some(ref info_queue) = info_queue {
+            info_queue.print();
         }
-        let device = init_device(&adapter, config.feature_level)?;
+        renderer.map(|mut renderer| {
+            renderer.leak_check = leak_check.take();
+            renderer
+        })
+    }
 
-        let _fence = create_fence(&device, D3D12_FENCE_FLAG_NONE)?;
+    /// Everything in `create()` after we have a device, which we may lose.
+    fn create_on_device(config:       &config::Config,
+                        h_wnd:        HWND,
+                        dxgi_factory: &ComPtr<IDXGIFactory4>,
+                        device:       &ComPtr<ID3D12Device>)
+        -> WindowsResult<Renderer>
+    {
+        let _fence = create_fence(device, D3D12_FENCE_FLAG_NONE)?;
 
         let _rtvd_size = unsafe {
             device.GetDescriptorHandleIncrementSize(D3D12_DESCRIPTOR_HEAP_TYPE_RTV)
@@ -153,17 +179,18 @@ impl Renderer {
             Flags:              D3D12_MULTISAMPLE_QUALITY_LEVELS_FLAG_NONE,
             NumQualityLevels:   0,
         };
-        check_feature_multisample_quality(&device, &mut ms_quality)?;
+        check_feature_multisample_quality(device, &mut ms_quality)?;
         println!("MS Quality: {}", ms_quality.NumQualityLevels);
 
-        let cmd_queue = init_cmd_queue(&device)?;
-        let cmd_alloc = init_cmd_alloc(&device)?;
-        let _gfx_cmd_list = init_gfx_cmd_list(&device, &cmd_alloc)?;
+        let cmd_queue = init_cmd_queue(device).context("creating the command queue")?;
+        let cmd_alloc = init_cmd_alloc(device).context("creating the command allocator")?;
+        let _gfx_cmd_list = init_gfx_cmd_list(device, &cmd_alloc)
+            .context("creating the graphics command list")?;
 
         let swapchain_desc = DXGI_SWAP_CHAIN_DESC {
             BufferDesc: DXGI_MODE_DESC {
-                Width:  1024,
-                Height: 1024,
+                Width:  config.resolution.width,
+                Height: config.resolution.height,
                 RefreshRate: DXGI_RATIONAL { Numerator: 60, Denominator: 1},
                 Format: backbuffer_format,
                 ScanlineOrdering: DXGI_MODE_SCANLINE_ORDER_UNSPECIFIED,
@@ -180,12 +207,13 @@ impl Renderer {
             SwapEffect: DXGI_SWAP_EFFECT_FLIP_DISCARD,
             Flags: DXGI_SWAP_CHAIN_FLAG_ALLOW_MODE_SWITCH,
         };
-        println!("{:#?}\n", swapchain_desc);
-        let _swapchain = init_swapchain(&dxgi_factory,
+        manifest::set_swapchain(swapchain_info(&swapchain_desc));
+        let _swapchain = init_swapchain(dxgi_factory,
                                        &cmd_queue,
-                                       swapchain_desc)?;
+                                       swapchain_desc)
+            .context("creating the swapchain")?;
 
-        Err(WindowsError::NotImplemented)
+        Err(WindowsError::not_implemented())
     }
 
     /// Update inter-frame state.
@@ -198,7 +226,7 @@ impl Renderer {
     }
 
     /// Generate and present a single frame.
-    pub fn render(&mut self) {
+    pub fn render(&mut self) -> WindowsResult<()> {
         // Populate the command list
         //   Reset self.cmd_alloc
         //   Reset the command list
@@ -209,10 +237,109 @@ impl Renderer {
         //   Resource barrier - backbuffer presenting after cmd_list
         //   Close the command list
         // Exec command list
+        //   Clear the backbuffer to self.clear_color
         // Present the frame
         // Wait for GPU to finish
 
         let _this_frame = &mut self.per_frame;
+
+        self.present()?;
+
+        if let Some(ref info_queue) = self.info_queue {
+            info_queue.print();
+        }
+
+        Ok(())
+    }
+
+    /// What we created the device on, e.g. `adapter 0 (...) at 12_0`.
+    pub fn attempt(&self) -> &Attempt {
+        &self.attempt
+    }
+
+    /// Take the settings in `config::LIVE_KEYS` from `config`, resizing the
+    /// swapchain if the resolution changed.
+    pub fn apply_live(&mut self, config: &config::Config) -> WindowsResult<()> {
+        self.vsync = config.vsync;
+        self.clear_color = config.clear_color.to_f32s();
+
+        let (width, height) = (config.resolution.width, config.resolution.height);
+        if self.viewport.Width as u32 != width || self.viewport.Height as u32 != height {
+            let result = self.resize(width, height);
+            result.map_err(|err| report_device_removed(&self.device, err))
+                .context("resizing the swapchain")?;
+        }
+        Ok(())
+    }
+
+    fn present(&mut self) -> WindowsResult<()> {
+        // A sync interval of 0 presents immediately, tearing and all.
+        let sync_interval = if self.vsync { 1 } else { 0 };
+        unsafe {
+            hr!(self.swapchain.Present(sync_interval, 0))?;
+        }
+        Ok(())
+    }
+
+    /// Resize the swapchain's buffers, and everything that depends on them.
+    fn resize(&mut self, width: u32, height: u32) -> WindowsResult<()> {
+        self.wait_for_gpu()?;
+        self.per_frame.clear();
+
+        let mut desc: DXGI_SWAP_CHAIN_DESC = unsafe { mem::zeroed() };
+        unsafe {
+            hr!(self.swapchain.GetDesc(&mut desc))?;
+            hr!(self.swapchain.ResizeBuffers(FRAME_COUNT as u32,
+                                             width,
+                                             height,
+                                             DXGI_FORMAT_UNKNOWN, // Keep the format
+                                             desc.Flags))?;
+        }
+        desc.BufferDesc.Width = width;
+        desc.BufferDesc.Height = height;
+        manifest::set_swapchain(swapchain_info(&desc));
+
+        let mut rtv_handle = unsafe { self.rtv_heap.GetCPUDescriptorHandleForHeapStart() };
+        for i in 0..FRAME_COUNT {
+            let buffer: ComPtr<ID3D12Resource> = unsafe {
+                let mut ptr: *mut _ = ptr::null_mut();
+                hr!(self.swapchain.GetBuffer(i as u32,
+                                             &ID3D12Resource::uuidof(),
+                                             &mut ptr as *mut _ as *mut _))?;
+                ComPtr::from_raw(ptr)
+            };
+            unsafe {
+                self.device.CreateRenderTargetView(buffer.as_raw(), ptr::null(), rtv_handle);
+            }
+            rtv_handle.ptr += self.rtvd_size as usize;
+            self.per_frame.push(PerFrame { rt_view: buffer });
+        }
+
+        self.viewport.Width = width as f32;
+        self.viewport.Height = height as f32;
+        self.scissor.right = width as i32;
+        self.scissor.bottom = height as i32;
+        self.frame_idx = unsafe { self.swapchain.GetCurrentBackBufferIndex() } as usize;
+        Ok(())
+    }
+
+    /// Block until the GPU has finished everything we've given it.
+    fn wait_for_gpu(&mut self) -> WindowsResult<()> {
+        self.fence_value += 1;
+        unsafe {
+            hr!(self.cmd_queue.Signal(self.fence.as_raw(), self.fence_value))?;
+            hr!(self.fence.SetEventOnCompletion(self.fence_value, self.fence_event))?;
+            WaitForSingleObject(self.fence_event, INFINITE);
+        }
+        Ok(())
+    }
+}
+
+impl Recoverable for Renderer {
+    fn frame(&mut self) -> WindowsResult<()> {
+        self.update();
+        let result = self.render();
+        result.map_err(|err| report_device_removed(&self.device, err))
     }
 }
 
@@ -226,6 +353,122 @@ impl Drop for Renderer {
     }
 }
 
+/// If `err` means we lost `device`, print and record what DRED knows about why.
+fn report_device_removed(device: &ComPtr<ID3D12Device>, err: WindowsError) -> WindowsError {
+    if err.category() != Category::DeviceRemoved {
+        return err;
+    }
+    match dred::read(device) {
+        Ok(report) => {
+            let mut text = vec![];
+            let _ = dred::write_report(&mut text, &report);
+            let text = String::from_utf8_lossy(&text).into_owned();
+            eprint!("{}", text);
+            crash::set_dred(text);
+        },
+        Err(dred_err) => {
+            print_error(&dred_err.context("reading Device Removed Extended Data"));
+        },
+    }
+    err
+}
+
+/// Print every adapter, and which one `config` selects. For `dx12 adapters`.
+pub fn print_adapters(config: &config::Config) -> WindowsResult<()> {
+    let dxgi_factory = init_dxgi_factory().context("creating the DXGI factory")?;
+    let adapters = enum_adapters(&dxgi_factory).context("enumerating adapters")?;
+    print_adapter_list(&adapters)?;
+
+    println!();
+    if config.force_warp {
+        println!("Selected: WARP (--force-warp)");
+    } else {
+        match config.adapter.select(&adapter_descs(&adapters)?) {
+            Some(i) => println!("Selected: adapter {} (--adapter {})", i, config.adapter),
+            None    => println!("Selected: nothing matches --adapter {}", config.adapter),
+        }
+    }
+
+    println!();
+    println!("Creating a device tries, in order (--fallback {}):", config.fallback);
+    for (attempt, i) in fallback::plan(config, &adapter_descs(&adapters)?).iter().zip(1..) {
+        println!("    {}. {}", i, attempt);
+    }
+    Ok(())
+}
+
+/// Create a device on the selected adapter, without a window or swapchain,
+/// and print what it supports. For `dx12 caps`.
+pub fn print_caps(config: &config::Config) -> WindowsResult<()> {
+    let dxgi_factory = init_dxgi_factory().context("creating the DXGI factory")?;
+    let adapters = enum_adapters(&dxgi_factory).context("enumerating adapters")?;
+    let (adapter, device, attempt) = create_device(config, &dxgi_factory, &adapters)?;
+    let range = attempt_range(config, &attempt);
+    let feature_level = max_supported_feature_level(&device, range)
+        .context("checking supported feature levels")?;
+
+    println!("Adapter:");
+    print!("{}", format_adapter_desc(&get_adapter_desc(&adapter)?));
+
+    let mut options: D3D12_FEATURE_DATA_D3D12_OPTIONS = unsafe { mem::zeroed() };
+    check_feature_support(&device, D3D12_FEATURE_D3D12_OPTIONS, &mut options)
+        .context("checking D3D12 options")?;
+    let mut architecture: D3D12_FEATURE_DATA_ARCHITECTURE = unsafe { mem::zeroed() };
+    check_feature_support(&device, D3D12_FEATURE_ARCHITECTURE, &mut architecture)
+        .context("checking the GPU architecture")?;
+
+    println!("Device (created at feature level {}):", attempt.feature_level);
+    println!("    MaxSupportedFeatureLevel:      {} (of {})", feature_level, range);
+    print!("{}", format_options(&options));
+    print!("{}", format_architecture(&architecture));
+    Ok(())
+}
+
+/// Create a device on the adapter `config` asks for, or whatever
+/// `fallback::plan()` comes up with after that. Returns the adapter and which
+/// attempt worked, too.
+fn create_device(config:       &config::Config,
+                 dxgi_factory: &ComPtr<IDXGIFactory4>,
+                 adapters:     &[ComPtr<IDXGIAdapter3>])
+    -> WindowsResult<(ComPtr<IDXGIAdapter>, ComPtr<ID3D12Device>, Attempt)>
+{
+    let attempts = fallback::plan(config, &adapter_descs(adapters)?);
+    if attempts.is_empty() {
+        return Err(WindowsError::new(ErrorKind::NoAdapter)
+                       .context(format!("selecting adapter \"{}\"", config.adapter)));
+    }
+
+    let ((adapter, device), attempt) = fallback::create_first(&attempts, |attempt| {
+            let adapter: ComPtr<IDXGIAdapter> = match attempt.adapter {
+                AdapterChoice::Index(i) => adapters[i].cast()?,
+                AdapterChoice::Warp     => {
+                    init_warp_adapter(dxgi_factory).context("finding the WARP adapter")?
+                },
+            };
+            let device = init_device(&adapter, attempt.feature_level)?;
+            Ok((adapter, device))
+        })
+        .map_err(|err| {
+            // Leave losing the device in its own category, so it can be retried.
+            if err.category() == Category::DeviceRemoved {
+                err
+            } else {
+                err.with_category(Category::DeviceCreation)
+            }
+        })
+        .context("creating the device")?;
+    Ok((adapter, device, attempt))
+}
+
+/// The levels to ask a device from `attempt` about. We might have fallen
+/// back below the minimum we were asked for.
+fn attempt_range(config: &config::Config, attempt: &Attempt) -> config::FeatureLevelRange {
+    config::FeatureLevelRange {
+        min: attempt.feature_level,
+        max: cmp::max(attempt.feature_level, config.feature_level.max),
+    }
+}
+
 fn enum_adapters(dxgi_factory: &ComPtr<IDXGIFactory4>) -> WindowsResult<Vec<ComPtr<IDXGIAdapter3>>> {
     let mut adapters: Vec<ComPtr<IDXGIAdapter3>> = vec![];
     unsafe {
@@ -242,38 +485,150 @@ fn enum_adapters(dxgi_factory: &ComPtr<IDXGIFactory4>) -> WindowsResult<Vec<ComP
             adapters.push(ComPtr::from_raw(adapter as *mut _));
         }
     }
+    Ok(adapters)
+}
 
-    for (adapter, i) in adapters.iter().zip(1..) {
-        let desc: DXGI_ADAPTER_DESC = unsafe {
-            let mut desc: DXGI_ADAPTER_DESC = mem::zeroed();
-            hr!(adapter.GetDesc(&mut desc as *mut _))?;
-            println!("Adapter {}:", i);
-            desc
-        };
+fn print_adapter_list(adapters: &[ComPtr<IDXGIAdapter3>]) -> WindowsResult<()> {
+    // Numbered from 0, like `--adapter` counts them.
+    for (adapter, i) in adapters.iter().zip(0..) {
+        let desc = get_adapter_desc(adapter)?;
+        println!("Adapter {}:", i);
+        print!("{}", format_adapter_desc(&desc));
+    }
+    Ok(())
+}
 
-        // Encooooodingggggggggggggggg
-        let description_string = {
-            use std::ffi::OsString;
-            use std::os::windows::prelude::*;
-            OsString::from_wide(&desc.Description)
-                .into_string()
-                .unwrap_or_else(|_os_str| "<Invalid Description String>".into())
-        };
-        let description = description_string.trim_matches(|c| {
-                c == '\n' || c == '\r' || c == '\0'
-        });
+fn adapter_descs(adapters: &[ComPtr<IDXGIAdapter3>]) -> WindowsResult<Vec<AdapterDesc>> {
+    adapters.iter()
+        .map(|adapter| get_adapter_desc1(adapter).map(|desc| to_adapter_desc(&desc)))
+        .collect()
+}
 
-        println!("    Description:           {}",     description);
-        println!("    Vendor:                {}",     vid_to_vendor(desc.VendorId));
-        println!("    DeviceId:              0x{:x}", desc.DeviceId);
-        println!("    SubSysId:              0x{:x}", desc.SubSysId);
-        println!("    Revision:              {}",     desc.Revision);
-        println!("    DedicatedVideoMemory:  0x{:x}", desc.DedicatedVideoMemory);
-        println!("    DedicatedSystemMemory: 0x{:x}", desc.DedicatedSystemMemory);
-        println!("    SharedSystemMemory:    0x{:x}", desc.SharedSystemMemory);
+fn get_adapter_desc(adapter: &IDXGIAdapter) -> WindowsResult<DXGI_ADAPTER_DESC> {
+    unsafe {
+        let mut desc: DXGI_ADAPTER_DESC = mem::zeroed();
+        hr!(adapter.GetDesc(&mut desc as *mut _))?;
+        Ok(desc)
     }
+}
 
-    Ok(adapters)
+fn get_adapter_desc1(adapter: &IDXGIAdapter1) -> WindowsResult<DXGI_ADAPTER_DESC1> {
+    unsafe {
+        let mut desc: DXGI_ADAPTER_DESC1 = mem::zeroed();
+        hr!(adapter.GetDesc1(&mut desc as *mut _))?;
+        Ok(desc)
+    }
+}
+
+/// What `--adapter` gets to choose from.
+fn to_adapter_desc(desc: &DXGI_ADAPTER_DESC1) -> AdapterDesc {
+    AdapterDesc {
+        description:            description_string(&desc.Description),
+        vendor:                 vid_to_vendor(desc.VendorId),
+        device_id:              desc.DeviceId,
+        luid:                   Luid::from_parts(desc.AdapterLuid.HighPart,
+                                                 desc.AdapterLuid.LowPart),
+        dedicated_video_memory: desc.DedicatedVideoMemory as u64,
+        shared_system_memory:   desc.SharedSystemMemory as u64,
+        software:               desc.Flags & DXGI_ADAPTER_FLAG_SOFTWARE != 0,
+    }
+}
+
+fn description_string(description: &[u16]) -> String {
+    // Encooooodingggggggggggggggg
+    let description_string = {
+        use std::ffi::OsString;
+        use std::os::windows::prelude::*;
+        OsString::from_wide(description)
+            .into_string()
+            .unwrap_or_else(|_os_str| "<Invalid Description String>".into())
+    };
+    description_string.trim_matches(|c| {
+            c == '\n' || c == '\r' || c == '\0'
+    }).to_string()
+}
+
+/// One indented line per field, like we print while enumerating adapters.
+fn format_adapter_desc(desc: &DXGI_ADAPTER_DESC) -> String {
+    let description = description_string(&desc.Description);
+
+    let mut s = String::new();
+    s += &format!("    Description:           {}\n",     description);
+    s += &format!("    Vendor:                {}\n",     vid_to_vendor(desc.VendorId));
+    s += &format!("    DeviceId:              0x{:x}\n", desc.DeviceId);
+    s += &format!("    SubSysId:              0x{:x}\n", desc.SubSysId);
+    s += &format!("    Revision:              {}\n",     desc.Revision);
+    s += &format!("    DedicatedVideoMemory:  0x{:x}\n", desc.DedicatedVideoMemory);
+    s += &format!("    DedicatedSystemMemory: 0x{:x}\n", desc.DedicatedSystemMemory);
+    s += &format!("    SharedSystemMemory:    0x{:x}\n", desc.SharedSystemMemory);
+    s += &format!("    AdapterLuid:           {}\n",     Luid::from_parts(desc.AdapterLuid.HighPart,
+                                                                         desc.AdapterLuid.LowPart));
+    s
+}
+
+/// One indented line per field of `D3D12_FEATURE_DATA_D3D12_OPTIONS`
+/// that's worth knowing.
+fn format_options(options: &D3D12_FEATURE_DATA_D3D12_OPTIONS) -> String {
+    fn tier(tier: u32) -> String {
+        match tier {
+            0    => "not supported".into(),
+            tier => format!("tier {}", tier),
+        }
+    }
+    let mut s = String::new();
+    s += &format!("    ResourceBindingTier:           {}\n", tier(options.ResourceBindingTier));
+    s += &format!("    ResourceHeapTier:              {}\n", tier(options.ResourceHeapTier));
+    s += &format!("    TiledResourcesTier:            {}\n", tier(options.TiledResourcesTier));
+    s += &format!("    ConservativeRasterizationTier: {}\n", tier(options.ConservativeRasterizationTier));
+    s += &format!("    CrossNodeSharingTier:          {}\n", tier(options.CrossNodeSharingTier));
+    s += &format!("    ROVsSupported:                 {}\n", yes_no(options.ROVsSupported));
+    s += &format!("    DoublePrecisionFloatShaderOps: {}\n", yes_no(options.DoublePrecisionFloatShaderOps));
+    s += &format!("    TypedUAVLoadAdditionalFormats: {}\n", yes_no(options.TypedUAVLoadAdditionalFormats));
+    s += &format!("    StandardSwizzle64KBSupported:  {}\n", yes_no(options.StandardSwizzle64KBSupported));
+    s += &format!("    MaxGPUVirtualAddressBitsPerResource: {}\n",
+                  options.MaxGPUVirtualAddressBitsPerResource);
+    s
+}
+
+fn format_architecture(architecture: &D3D12_FEATURE_DATA_ARCHITECTURE) -> String {
+    let mut s = String::new();
+    s += &format!("    TileBasedRenderer:             {}\n", yes_no(architecture.TileBasedRenderer));
+    s += &format!("    UMA:                           {}\n", yes_no(architecture.UMA));
+    s += &format!("    CacheCoherentUMA:              {}\n", yes_no(architecture.CacheCoherentUMA));
+    s
+}
+
+/// What a manifest records about `desc`.
+fn swapchain_info(desc: &DXGI_SWAP_CHAIN_DESC) -> SwapchainInfo {
+    let format = match desc.BufferDesc.Format {
+        DXGI_FORMAT_R8G8B8A8_UNORM      => "R8G8B8A8_UNORM".to_string(),
+        DXGI_FORMAT_R8G8B8A8_UNORM_SRGB => "R8G8B8A8_UNORM_SRGB".to_string(),
+        DXGI_FORMAT_B8G8R8A8_UNORM      => "B8G8R8A8_UNORM".to_string(),
+        DXGI_FORMAT_B8G8R8A8_UNORM_SRGB => "B8G8R8A8_UNORM_SRGB".to_string(),
+        DXGI_FORMAT_R10G10B10A2_UNORM   => "R10G10B10A2_UNORM".to_string(),
+        DXGI_FORMAT_R16G16B16A16_FLOAT  => "R16G16B16A16_FLOAT".to_string(),
+        format                          => format!("DXGI_FORMAT {}", format),
+    };
+    let swap_effect = match desc.SwapEffect {
+        DXGI_SWAP_EFFECT_DISCARD         => "DISCARD".to_string(),
+        DXGI_SWAP_EFFECT_SEQUENTIAL      => "SEQUENTIAL".to_string(),
+        DXGI_SWAP_EFFECT_FLIP_SEQUENTIAL => "FLIP_SEQUENTIAL".to_string(),
+        DXGI_SWAP_EFFECT_FLIP_DISCARD    => "FLIP_DISCARD".to_string(),
+        swap_effect                      => format!("DXGI_SWAP_EFFECT {}", swap_effect),
+    };
+    SwapchainInfo {
+        width:        desc.BufferDesc.Width,
+        height:       desc.BufferDesc.Height,
+        format,
+        buffer_count: desc.BufferCount,
+        sample_count: desc.SampleDesc.Count,
+        swap_effect,
+        windowed:     desc.Windowed != 0,
+    }
+}
+
+fn yes_no(b: BOOL) -> &'static str {
+    if b != 0 { "yes" } else { "no" }
 }
 
 fn create_fence(device: &ComPtr<ID3D12Device>, flags: u32) -> WindowsResult<ComPtr<ID3D12Fence>> {
@@ -301,6 +656,54 @@ fn check_feature_multisample_quality(
     Ok(())
 }
 
+fn check_feature_support<T>(device:  &ComPtr<ID3D12Device>,
+                            feature: D3D12_FEATURE,
+                            data:    &mut T)
+    -> WindowsResult<()>
+{
+    unsafe {
+        hr!(device.CheckFeatureSupport(feature,
+                                       data as *mut _ as *mut _,
+                                       mem::size_of_val(data) as u32))?;
+    }
+    Ok(())
+}
+
+/// The highest level in `range` that `device` supports.
+fn max_supported_feature_level(device: &ComPtr<ID3D12Device>,
+                               range:  config::FeatureLevelRange)
+    -> WindowsResult<config::Dx12FeatureLevel>
+{
+    let mut levels: Vec<u32> = range.levels().iter().map(|level| level.to_u32()).collect();
+    loop {
+        let mut data = D3D12_FEATURE_DATA_FEATURE_LEVELS {
+            NumFeatureLevels:         levels.len() as u32,
+            pFeatureLevelsRequested:  levels.as_ptr(),
+            MaxSupportedFeatureLevel: 0,
+        };
+        // Runtimes older than a level reject it, so we expect failures, and
+        // don't go through `hr!`, which would record them as the last one.
+        let hresult = unsafe {
+            device.CheckFeatureSupport(D3D12_FEATURE_FEATURE_LEVELS,
+                                       &mut data as *mut _ as *mut _,
+                                       mem::size_of_val(&data) as u32)
+        };
+        if winerror::SUCCEEDED(hresult) {
+            let max = data.MaxSupportedFeatureLevel;
+            return config::Dx12FeatureLevel::from_u32(max).ok_or_else(|| {
+                WindowsError::from(winerror::E_UNEXPECTED)
+                    .context(format!("the device says it supports 0x{:x}, \
+                                      which isn't a level we asked about", max))
+            });
+        }
+        if levels.len() == 1 {
+            return Err(WindowsError::from(hresult));
+        }
+        // Ask again without the highest level.
+        levels.pop();
+    }
+}
+
 // Initialization is a lot, so we break it apart into named functions.
 // You may notice some repetitive code: still working on how to make this pretty.
 
@@ -313,23 +716,11 @@ fn init_debug_objects() -> WindowsResult<()> {
     };
     unsafe { d3d12_debug.EnableDebugLayer(); }
 
-    let dxgi_debug: ComPtr<IDXGIDebug> = unsafe {
-        let mut ptr: *mut IDXGIDebug = ptr::null_mut();
-        // MS Docs:
-        //      The DXGIGetDebugInterface1 function returns E_NOINTERFACE on
-        //      systems without the Windows Software Development Kit (SDK)
-        //      installed, because it's a development-time aid.
-        // So we report but ignore an error here.
-        let _ = (|| -> WindowsResult<()> {
-            use winapi::shared::dxgi1_3::DXGIGetDebugInterface1;
-            hr!(DXGIGetDebugInterface1(0, // flags, unused
-                                       &IDXGIDebug::uuidof(),
-                                       &mut ptr as *mut _ as *mut _))?;
-            Ok(())
-        })();
-        ComPtr::from_raw(ptr)
-    };
-    unsafe { dxgi_debug.ReportLiveObjects(DXGI_DEBUG_ALL, DXGI_DEBUG_RLO_ALL); }
+    // DRED has to be on before we create the device, and older versions of
+    // Windows don't have it, so we report but ignore failures.
+    if let Err(err) = dred::enable() {
+        print_error(&err.context("enabling Device Removed Extended Data"));
+    }
 
     Ok(())
 }
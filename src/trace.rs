@@ -0,0 +1,303 @@
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use error::{CallSite, HResult};
+
+/// How many of the slowest calls the summary lists.
+const SLOWEST_COUNT: usize = 5;
+
+/// One `hr!` call, as seen with `--trace-api`.
+#[derive(Debug, Copy, Clone)]
+pub struct CallRecord {
+    pub call_site: CallSite,
+    pub duration:  Duration,
+    pub result:    HResult,
+}
+
+impl CallRecord {
+    /// The function being called, without its arguments.
+    /// e.g. `device.CreateCommandQueue`
+    pub fn name(&self) -> &'static str {
+        let expr = self.call_site.expr;
+        match expr.find('(') {
+            Some(paren) => expr[..paren].trim(),
+            None        => expr,
+        }
+    }
+}
+
+/// Calls aggregated as they come in, so a long trace doesn't grow without bound.
+#[derive(Default)]
+pub struct Summary {
+    calls:   usize,
+    total:   Duration,
+    /// In the order each site was first hit.
+    sites:   Vec<Site>,
+    /// Indices into `sites`, by file and line.
+    by_site: HashMap<(&'static str, u32), usize>,
+    /// The slowest `SLOWEST_COUNT` calls, slowest first.
+    slowest: Vec<CallRecord>,
+    failed:  Vec<CallRecord>,
+}
+
+struct Site {
+    record: CallRecord,
+    count:  usize,
+    total:  Duration,
+    max:    Duration,
+}
+
+impl Summary {
+    pub fn add(&mut self, record: CallRecord) {
+        self.calls += 1;
+        self.total += record.duration;
+
+        let key = (record.call_site.file, record.call_site.line);
+        match self.by_site.get(&key) {
+            Some(&i) => {
+                let site = &mut self.sites[i];
+                site.count += 1;
+                site.total += record.duration;
+                site.max = site.max.max(record.duration);
+            },
+            None => {
+                self.by_site.insert(key, self.sites.len());
+                self.sites.push(Site {
+                    record,
+                    count: 1,
+                    total: record.duration,
+                    max:   record.duration,
+                });
+            },
+        }
+
+        // Ties keep the earlier call first.
+        let position = self.slowest.iter()
+            .position(|slow| slow.duration < record.duration)
+            .unwrap_or_else(|| self.slowest.len());
+        if position < SLOWEST_COUNT {
+            self.slowest.insert(position, record);
+            self.slowest.truncate(SLOWEST_COUNT);
+        }
+
+        if record.result.failed() {
+            self.failed.push(record);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Trace {
+    enabled: bool,
+    summary: Summary,
+}
+
+thread_local! {
+    static TRACE: RefCell<Trace> = RefCell::new(Trace::default());
+}
+
+/// Start or stop recording `hr!` calls.
+pub fn set_enabled(enabled: bool) {
+    TRACE.with(|trace| trace.borrow_mut().enabled = enabled);
+}
+
+pub fn is_enabled() -> bool {
+    TRACE.try_with(|trace| {
+        trace.try_borrow().map(|trace| trace.enabled).unwrap_or(false)
+    }).unwrap_or(false)
+}
+
+/// Used by `hr!` before making its call. `None` when we're not tracing.
+pub fn start() -> Option<Instant> {
+    if is_enabled() {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+/// Used by `hr!` after making its call, with whatever `start()` returned.
+pub fn record(start: Option<Instant>, call_site: CallSite, result: i32) {
+    let start = match start {
+        Some(start) => start,
+        None        => return,
+    };
+    let record = CallRecord {
+        call_site,
+        duration: start.elapsed(),
+        result:   HResult(result),
+    };
+    let _ = TRACE.try_with(|trace| {
+        if let Ok(mut trace) = trace.try_borrow_mut() {
+            trace.summary.add(record);
+        }
+    });
+}
+
+/// Print a summary of every traced call to stderr, if we were tracing.
+pub fn print_summary() {
+    if !is_enabled() {
+        return;
+    }
+    let stderr = io::stderr();
+    TRACE.with(|trace| {
+        let _ = write_summary(&mut stderr.lock(), &trace.borrow().summary);
+    });
+}
+
+/// Write call counts, the slowest calls, and every failure.
+///
+/// ```text
+/// API trace: 12 calls, 48.213 ms total, 1 failed
+///
+/// Calls by site:
+///     count   total ms     max ms  call
+///         1     31.877     31.877  D3D12CreateDevice (src\renderer.rs:383)
+///     ...
+///
+/// Slowest calls:
+///        31.877 ms  D3D12CreateDevice (src\renderer.rs:383) -> S_OK
+///     ...
+///
+/// Failed calls:
+///     DXGIGetDebugInterface1 (src\renderer.rs:344) -> E_NOINTERFACE (facility NULL 0x0, code 0x4002)
+/// ```
+pub fn write_summary<W: Write>(w: &mut W, summary: &Summary) -> io::Result<()> {
+    writeln!(w, "API trace: {} calls, {:.3} ms total, {} failed",
+             summary.calls,
+             millis(summary.total),
+             summary.failed.len())?;
+    if summary.calls == 0 {
+        return Ok(());
+    }
+
+    writeln!(w)?;
+    writeln!(w, "Calls by site:")?;
+    writeln!(w, "    {:>5} {:>10} {:>10}  call", "count", "total ms", "max ms")?;
+    for site in &summary.sites {
+        writeln!(w, "    {:>5} {:>10.3} {:>10.3}  {}",
+                 site.count,
+                 millis(site.total),
+                 millis(site.max),
+                 describe(&site.record))?;
+    }
+
+    writeln!(w)?;
+    writeln!(w, "Slowest calls:")?;
+    for record in &summary.slowest {
+        writeln!(w, "    {:>10.3} ms  {} -> {}",
+                 millis(record.duration),
+                 describe(record),
+                 result_name(record.result))?;
+    }
+
+    if !summary.failed.is_empty() {
+        writeln!(w)?;
+        writeln!(w, "Failed calls:")?;
+        for record in &summary.failed {
+            writeln!(w, "    {} -> {}", describe(record), record.result)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(record: &CallRecord) -> String {
+    format!("{} ({}:{})",
+            record.name(),
+            record.call_site.file,
+            record.call_site.line)
+}
+
+fn result_name(result: HResult) -> String {
+    match result.name() {
+        Some(name) => name.to_string(),
+        None       => format!("0x{:08x}", result.0),
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(expr: &'static str, line: u32, millis: u64, result: u32) -> CallRecord {
+        CallRecord {
+            call_site: CallSite { file: "src/renderer.rs", line, expr },
+            duration:  Duration::from_millis(millis),
+            result:    HResult(result as i32),
+        }
+    }
+
+    fn summary(records: &[CallRecord]) -> String {
+        let mut summary = Summary::default();
+        for &record in records {
+            summary.add(record);
+        }
+        let mut text = vec![];
+        write_summary(&mut text, &summary).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn names_calls_without_their_arguments() {
+        assert_eq!(call("device.CreateCommandQueue(&desc, &mut queue)", 1, 0, 0).name(),
+                   "device.CreateCommandQueue");
+        assert_eq!(call("D3D12CreateDevice\n    (adapter, level)", 1, 0, 0).name(), "D3D12CreateDevice");
+        assert_eq!(call("hresult", 1, 0, 0).name(), "hresult");
+    }
+
+    #[test]
+    fn summarizes_nothing() {
+        assert_eq!(summary(&[]), "API trace: 0 calls, 0.000 ms total, 0 failed\n");
+    }
+
+    #[test]
+    fn groups_calls_by_site() {
+        let text = summary(&[call("queue.Signal(fence, 1)",  20, 2, 0),
+                             call("swapchain.Present(1, 0)", 30, 5, 0),
+                             call("queue.Signal(fence, 2)",  20, 4, 0),
+                             call("DXGIGetDebugInterface1(0)", 10, 1, 0x80004002)]);
+        assert_eq!(text, "\
+API trace: 4 calls, 12.000 ms total, 1 failed
+
+Calls by site:
+    count   total ms     max ms  call
+        2      6.000      4.000  queue.Signal (src/renderer.rs:20)
+        1      5.000      5.000  swapchain.Present (src/renderer.rs:30)
+        1      1.000      1.000  DXGIGetDebugInterface1 (src/renderer.rs:10)
+
+Slowest calls:
+         5.000 ms  swapchain.Present (src/renderer.rs:30) -> S_OK
+         4.000 ms  queue.Signal (src/renderer.rs:20) -> S_OK
+         2.000 ms  queue.Signal (src/renderer.rs:20) -> S_OK
+         1.000 ms  DXGIGetDebugInterface1 (src/renderer.rs:10) -> E_NOINTERFACE
+
+Failed calls:
+    DXGIGetDebugInterface1 (src/renderer.rs:10) -> E_NOINTERFACE (facility NULL 0x0, code 0x4002)
+");
+    }
+
+    #[test]
+    fn keeps_only_the_slowest_calls() {
+        let records: Vec<_> = (1..=20)
+            .map(|millis| call("Present()", millis as u32, millis, 0))
+            .collect();
+        let text = summary(&records);
+        let slowest: Vec<_> = text.lines()
+            .skip_while(|line| *line != "Slowest calls:")
+            .skip(1)
+            .collect();
+        assert_eq!(slowest.len(), SLOWEST_COUNT);
+        assert!(slowest[0].starts_with("        20.000 ms"), "{}", slowest[0]);
+        assert!(slowest[SLOWEST_COUNT - 1].starts_with("        16.000 ms"));
+    }
+}
@@ -1,19 +1,23 @@
 
+use error::WindowsResult;
+
 use winapi::{
-    shared::winerror::HRESULT,
     shared::minwindef::HINSTANCE,
     shared::windef::HBRUSH,
     shared::windef::HWND,
+    shared::windef::RECT,
 
-    um::errhandlingapi::GetLastError,
     um::libloaderapi::GetModuleHandleA,
     um::wingdi::GetStockObject,
     um::wingdi::WHITE_BRUSH,
     um::winuser::{
+        AdjustWindowRect,
         CreateWindowExA,
         LoadCursorW,
         LoadIconW,
         RegisterClassA,
+        SetWindowPos,
+        SetWindowTextA,
         ShowWindow,
         UpdateWindow,
         CS_HREDRAW,
@@ -21,12 +25,16 @@ use winapi::{
         CW_USEDEFAULT,
         IDC_ARROW,
         IDI_APPLICATION,
+        SWP_NOACTIVATE,
+        SWP_NOMOVE,
+        SWP_NOZORDER,
         WNDCLASSA,
         WS_OVERLAPPEDWINDOW,
     },
 };
 
 use std::{
+    ffi::CString,
     ptr,
 };
 
@@ -63,7 +71,10 @@ extern "system" fn wnd_proc(h_wnd:   HWND,
     }
 }
 
-pub fn init_window(window_title: &str) -> Result<HWND, HRESULT> {
+/// Make a window whose client area is `width` by `height`.
+pub fn init_window(window_title: &str, width: u32, height: u32) -> WindowsResult<HWND> {
+    // Titles come from us, so an interior nul is a bug.
+    let window_title = CString::new(window_title).expect("window title has a nul in it");
     unsafe {
         let h_instance = GetModuleHandleA(ptr::null_mut()) as HINSTANCE;
 
@@ -77,25 +88,26 @@ pub fn init_window(window_title: &str) -> Result<HWND, HRESULT> {
             hCursor:       LoadCursorW(ptr::null_mut(), IDC_ARROW),
             hbrBackground: GetStockObject(WHITE_BRUSH as i32) as HBRUSH,
             lpszMenuName:  ptr::null_mut(),
-            lpszClassName: b"BasicWndClass".as_ptr() as *const i8,
+            lpszClassName: b"BasicWndClass\0".as_ptr() as *const i8,
         };
 
-        if RegisterClassA(&wc) == 0 {
-            hr!(GetLastError() as i32)?;
-        }
+        win32!(RegisterClassA(&wc))?;
+
+        // CreateWindow wants the size of the whole window, borders and all.
+        let rect = window_rect(width, height)?;
 
-        let h_wnd = CreateWindowExA(0x0,                 // Ex style flags
-                                    wc.lpszClassName,
-                                    window_title.as_ptr() as *const i8,
-                                    WS_OVERLAPPEDWINDOW, // Style flags
-                                    CW_USEDEFAULT,       // x-coord
-                                    CW_USEDEFAULT,       // y-coord
-                                    CW_USEDEFAULT,       // width
-                                    CW_USEDEFAULT,       // height
-                                    ptr::null_mut(),     // Parent window
-                                    ptr::null_mut(),     // Menu handle
-                                    h_instance,
-                                    ptr::null_mut()      /*Extra params*/);
+        let h_wnd = win32!(CreateWindowExA(0x0,                 // Ex style flags
+                                           wc.lpszClassName,
+                                           window_title.as_ptr(),
+                                           WS_OVERLAPPEDWINDOW, // Style flags
+                                           CW_USEDEFAULT,       // x-coord
+                                           CW_USEDEFAULT,       // y-coord
+                                           rect.right - rect.left,
+                                           rect.bottom - rect.top,
+                                           ptr::null_mut(),     // Parent window
+                                           ptr::null_mut(),     // Menu handle
+                                           h_instance,
+                                           ptr::null_mut()      /*Extra params*/))?;
 
         ShowWindow(h_wnd, 1);
         UpdateWindow(h_wnd);
@@ -103,3 +115,43 @@ pub fn init_window(window_title: &str) -> Result<HWND, HRESULT> {
         Ok(h_wnd)
     }
 }
+
+/// Resize `h_wnd` so its client area is `width` by `height`, keeping it where
+/// it is.
+pub fn resize_window(h_wnd: HWND, width: u32, height: u32) -> WindowsResult<()> {
+    let rect = window_rect(width, height)?;
+    unsafe {
+        win32!(SetWindowPos(h_wnd,
+                            ptr::null_mut(), // Ignored with SWP_NOZORDER
+                            0,               // x, ignored with SWP_NOMOVE
+                            0,               // y, ignored with SWP_NOMOVE
+                            rect.right - rect.left,
+                            rect.bottom - rect.top,
+                            SWP_NOACTIVATE | SWP_NOMOVE | SWP_NOZORDER))?;
+    }
+    Ok(())
+}
+
+pub fn set_title(h_wnd: HWND, title: &str) -> WindowsResult<()> {
+    // Titles come from us, so an interior nul is a bug.
+    let title = CString::new(title).expect("window title has a nul in it");
+    unsafe {
+        win32!(SetWindowTextA(h_wnd, title.as_ptr()))?;
+    }
+    Ok(())
+}
+
+/// The size of a window, borders and all, whose client area is `width` by
+/// `height`.
+fn window_rect(width: u32, height: u32) -> WindowsResult<RECT> {
+    let mut rect = RECT {
+        left:   0,
+        top:    0,
+        right:  width as i32,
+        bottom: height as i32,
+    };
+    unsafe {
+        win32!(AdjustWindowRect(&mut rect, WS_OVERLAPPEDWINDOW, 0 /* no menu */))?;
+    }
+    Ok(rect)
+}